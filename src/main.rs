@@ -3,16 +3,24 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{ArgGroup, CommandFactory, Parser};
+use regex::Regex;
 use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM};
 use signal_hook::iterator::Signals;
-use std::env;
 use std::path::PathBuf;
 use std::process::exit;
 use std::thread;
+use std::time::{Duration, Instant};
 
 mod wlib;
+use wlib::environ::parse_env_var;
+use wlib::exitcodes::ExitCodes;
+use wlib::helpers::{parse_backoff, parse_duration, parse_size, parse_umask};
 use wlib::manager::RunManager;
+use wlib::period::{parse_period, Period};
+use wlib::priority::{parse_ionice, IoPriority};
+use wlib::signals as wsignals;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -22,6 +30,8 @@ use wlib::manager::RunManager;
     about=crate_description!(),
     long_about=None
 )]
+// The command is run under the shell with either of these
+#[command(group(ArgGroup::new("shell_cmd").args(["bash_string", "step"]).multiple(true)))]
 struct Args {
     /// The directory to write the state file to
     #[arg(short = 'd', long, default_value = "/var/tmp")]
@@ -41,6 +51,29 @@ struct Args {
     /// to run while a previous instance was still running.
     #[arg(short, long, help_heading = "FAIL OPTS")]
     ignore_retry_fails: bool,
+    /// The number of times to immediately re-run the command if it fails,
+    /// before counting it as a failure.  Every attempt is included in the
+    /// report.
+    #[arg(long, default_value_t = 0, help_heading = "FAIL OPTS")]
+    retry_on_failure: usize,
+    /// How long to wait before re-running a failed command, e.g. 30s or 5m
+    #[arg(
+        long,
+        default_value = "30s",
+        value_parser = parse_duration,
+        help_heading = "FAIL OPTS"
+    )]
+    retry_delay: Duration,
+    /// Multiply the retry delay by this after each failed attempt, e.g. with
+    /// a delay of 30s and a backoff of 2, retries wait 30s, 60s, 120s...
+    /// This must be at least 1, and the delay is capped at a day.
+    #[arg(
+        long,
+        default_value_t = 1.0,
+        value_parser = parse_backoff,
+        help_heading = "FAIL OPTS"
+    )]
+    retry_backoff: f64,
     /// The number of consecutive failures that must occur
     /// before a report is printed.
     #[arg(short, long, default_value_t = 1, help_heading = "FAIL OPTS")]
@@ -58,21 +91,183 @@ struct Args {
     /// Use this for the PATH variable instead of the default.
     #[arg(short, long)]
     path: Option<String>,
+    /// Set an environment variable for the command in the form of
+    /// KEY=VALUE.  This can be specified multiple times.
+    #[arg(long, value_parser = parse_env_var, help_heading = "ENVIRONMENT")]
+    env: Vec<(String, String)>,
+    /// Load environment variables for the command from a file in dotenv
+    /// format (KEY=VALUE lines).  This can be specified multiple times and
+    /// variables set with '--env' take precedence.
+    #[arg(long, help_heading = "ENVIRONMENT")]
+    env_file: Vec<PathBuf>,
+    /// Start the command with an empty environment rather than inheriting
+    /// cwrap's.  Only variables from '--env', '--env-file' and '--path' are set.
+    #[arg(long, help_heading = "ENVIRONMENT")]
+    clear_env: bool,
+    /// Include the command's environment in failure reports.  The values of
+    /// variables that look like secrets (passwords, tokens, keys) are redacted.
+    #[arg(long, help_heading = "ENVIRONMENT")]
+    report_env: bool,
     /// If this flag is set, it signals that the command passed in
     /// should be run in a subshell as a single string.  This is useful for
     /// commands that include a '|' or similar character.
     /// Ex: `cat /tmp/file | grep stuff`"
     #[arg(short = 'g', long)]
     bash_string: bool,
+    /// The shell to run the command (or steps) under when '-g' or '--step'
+    /// is set.  The default is bash.
+    #[arg(long, requires = "shell_cmd")]
+    shell: Option<String>,
+    /// When '-g' is set, run the command in strict mode, which exits on the
+    /// first error and on use of an unset variable (set -eu).  Where the shell
+    /// supports it, a failure anywhere in a pipeline is also an error (set -o
+    /// pipefail), so `false | cat` is a failure.  This also applies to each
+    /// '--step'.
+    #[arg(long, requires = "shell_cmd")]
+    strict_shell: bool,
+    /// A comma separated list of exit codes, or ranges of them, that are
+    /// considered a successful run, e.g. "0,1,24" or "0,20-25".  Make sure
+    /// to include 0 if that is still a success.
+    #[arg(
+        long,
+        default_value = "0",
+        value_parser = ExitCodes::parse,
+        help_heading = "FAIL OPTS"
+    )]
+    ok_exit_codes: ExitCodes,
+    /// Consider the run a failure if the command writes anything to stderr
+    #[arg(long, help_heading = "FAIL OPTS")]
+    fail_on_stderr: bool,
+    /// Consider the run a failure if any line of stdout matches this regex.
+    /// This can be specified multiple times.
+    #[arg(long, value_parser = Regex::new, help_heading = "FAIL OPTS")]
+    fail_if_stdout_matches: Vec<Regex>,
+    /// Consider the run a failure if no line of output (stdout or stderr)
+    /// matches this regex.  This can be specified multiple times.
+    #[arg(long, value_parser = Regex::new, help_heading = "FAIL OPTS")]
+    fail_if_output_missing: Vec<Regex>,
+    /// Drop any line of output (stdout or stderr) matching this regex before
+    /// the output is checked or reported.  This can be specified multiple
+    /// times.  If all of a successful command's output is dropped, no
+    /// success report is printed.
+    #[arg(long, value_parser = Regex::new, help_heading = "FAIL OPTS")]
+    ignore_output: Vec<Regex>,
+    /// Read patterns for '--ignore-output' from this file, one per line.
+    /// Blank lines and lines starting with '#' are skipped.
+    #[arg(long, help_heading = "FAIL OPTS")]
+    ignore_output_file: Option<PathBuf>,
     /// The number of seconds to allow the command to run before timing it out.
     /// If set to zero (default), timeouts are disabled.
     #[arg(short, long, default_value_t = 0, help_heading = "FAIL OPTS")]
     timeout: usize,
+    /// Kill the command (and its process group) if it goes this long (e.g.
+    /// 10m) without writing anything to stdout or stderr.  This is reported
+    /// separately from '--timeout'.
+    #[arg(long, value_parser = parse_duration, help_heading = "FAIL OPTS")]
+    idle_timeout: Option<Duration>,
+    /// If a successful run takes longer than this (e.g. 10m), the command is
+    /// allowed to finish, but a warning report is generated and logged to
+    /// syslog.  This is reported even if '--quiet' is set.
+    #[arg(long, value_parser = parse_duration, help_heading = "FAIL OPTS")]
+    warn_after: Option<Duration>,
+    /// The time, e.g. 4m, from when cwrap starts that everything must be
+    /// done in.  This covers the fuzz, waiting for the lock and running the
+    /// command (including any retries), so a frequent job can't overlap with
+    /// its next run.  The command is killed if it's still running then.
+    /// This must be longer than '--fuzz'.
+    #[arg(long, value_parser = parse_duration, help_heading = "FAIL OPTS")]
+    deadline: Option<Duration>,
     /// This will add a random sleep between 0 and N seconds before
     /// executing the command.  Note that '--timeout' only pertains
     /// to command execution time.
     #[arg(short = 'z', long, default_value_t = 0)]
     fuzz: usize,
+    /// Instead of a random fuzz on each run, derive the delay from the
+    /// hostname and the command, so each host gets a stable offset within
+    /// the '--fuzz' window.  This spreads a job across a fleet while keeping
+    /// each host's schedule regular.
+    #[arg(long, requires = "fuzz")]
+    stable_fuzz: bool,
+    /// Run the command as this user (a name or uid) instead of the user
+    /// running cwrap.  This requires cwrap to be run as root.  The state and
+    /// lock files are still created by, and owned by, the invoking user, but
+    /// the '--stdin-file', '--env-file' and '--chdir' are only used if this
+    /// user can get to them.
+    #[arg(long, help_heading = "EXECUTION")]
+    user: Option<String>,
+    /// Run the command with this group (a name or gid).  If this isn't set
+    /// and '--user' is, the primary group of that user is used.  This
+    /// requires cwrap to be run as root.
+    #[arg(long, help_heading = "EXECUTION")]
+    group: Option<String>,
+    /// Read the command's stdin from this file.  By default, stdin is
+    /// /dev/null so a command can never block waiting for input.
+    #[arg(long, conflicts_with = "stdin_inherit", help_heading = "EXECUTION")]
+    stdin_file: Option<PathBuf>,
+    /// Let the command read from cwrap's own stdin instead of /dev/null
+    #[arg(long, help_heading = "EXECUTION")]
+    stdin_inherit: bool,
+    /// Make cwrap a child subreaper so that background processes the command
+    /// leaves behind are tracked.  cwrap waits for them to exit (within the
+    /// timeout) before the run is finished and the lock released.
+    #[arg(long, help_heading = "EXECUTION")]
+    subreaper: bool,
+    /// With '--subreaper', kill any processes left behind by the command
+    /// instead of waiting for them
+    #[arg(long, requires = "subreaper", help_heading = "EXECUTION")]
+    kill_orphans: bool,
+    /// Let the command inherit this open file descriptor from cwrap.  Only
+    /// stdin, stdout and stderr are passed on otherwise.  This can be
+    /// specified multiple times.
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(i32).range(3..),
+        help_heading = "EXECUTION"
+    )]
+    pass_fd: Vec<i32>,
+    /// Run the command attached to a pseudo-terminal instead of pipes.  This
+    /// is for tools that buffer or suppress their output when it isn't a
+    /// terminal.  Note that stdout and stderr are combined in this mode.
+    #[arg(long, help_heading = "EXECUTION")]
+    pty: bool,
+    /// Remove ANSI escape sequences (colors, cursor movement, etc.) from the
+    /// captured output.  This is mostly useful with '--pty'.
+    #[arg(long, help_heading = "EXECUTION")]
+    strip_ansi: bool,
+    /// Run the command in this directory instead of the current one
+    #[arg(long, help_heading = "EXECUTION")]
+    chdir: Option<PathBuf>,
+    /// Set the file mode creation mask (in octal, e.g. 027) for the command
+    #[arg(long, value_parser = parse_umask, help_heading = "EXECUTION")]
+    umask: Option<u32>,
+    /// Set the niceness (CPU scheduling priority) of the command, from -20
+    /// (highest priority) to 19 (lowest).  Negative values require root.
+    #[arg(
+        long,
+        allow_hyphen_values = true,
+        value_parser = clap::value_parser!(i32).range(-20..=19),
+        help_heading = "EXECUTION"
+    )]
+    nice: Option<i32>,
+    /// Set the I/O scheduling class and level of the command in the form of
+    /// <class>[:<level>], where class is one of realtime, best-effort or idle
+    /// (or 1-3 as with ionice) and level is 0-7.  Ex: best-effort:7
+    #[arg(long, value_parser = parse_ionice, help_heading = "EXECUTION")]
+    ionice: Option<IoPriority>,
+    /// Limit the address space (virtual memory) available to the command.
+    /// This is a size in bytes with an optional K, M, G or T suffix, e.g. 512M
+    #[arg(long, value_parser = parse_size, help_heading = "LIMITS")]
+    limit_mem: Option<u64>,
+    /// Limit the CPU time, in seconds, that the command can use
+    #[arg(long, help_heading = "LIMITS")]
+    limit_cpu: Option<u64>,
+    /// Limit the number of files the command can have open at once
+    #[arg(long, help_heading = "LIMITS")]
+    limit_nofile: Option<u64>,
+    /// Limit the size of any file the command writes.  This is a size in
+    /// bytes with an optional K, M, G or T suffix, e.g. 1G
+    #[arg(long, value_parser = parse_size, help_heading = "LIMITS")]
+    limit_fsize: Option<u64>,
     /// Only output error reports. If the command runs successfully,
     /// nothing will be printed, even if the command had stdout or stderr output.
     #[arg(short, long)]
@@ -83,7 +278,12 @@ struct Args {
     #[arg(short = 'S', long, help_heading = "SYSLOG")]
     syslog: bool,
     /// Set the logging facility.  The list of available facilities is here: http://t.ly/2nqs
-    #[arg(short = 'C', long, help_heading = "SYSLOG", default_value = "log_local7")]
+    #[arg(
+        short = 'C',
+        long,
+        help_heading = "SYSLOG",
+        default_value = "log_local7"
+    )]
     syslog_fac: String,
     /// Set the syslog priority
     #[arg(short = 'P', long, help_heading = "SYSLOG", default_value = "log_info")]
@@ -109,7 +309,12 @@ struct Args {
     #[arg(short = 'R', long, help_heading = "EMAIL")]
     recipient: Option<Vec<String>>,
     /// The subject to use for the email.
-    #[arg(short = 'J', long, help_heading = "EMAIL", default_value = "cwrap failure report")]
+    #[arg(
+        short = 'J',
+        long,
+        help_heading = "EMAIL",
+        default_value = "cwrap failure report"
+    )]
     subject: String,
     /// The SMTP server address (hostname or IP) to connect to.
     #[arg(short = 'X', long, help_heading = "EMAIL", default_value = "localhost")]
@@ -126,7 +331,7 @@ struct Args {
     #[arg(short = 'Z', long = "starttls", help_heading = "EMAIL")]
     starttls: bool,
     /// The username to use for SMTP authentication.
-    #[arg(short = 'U',long, help_heading = "EMAIL")]
+    #[arg(short = 'U', long, help_heading = "EMAIL")]
     username: Option<String>,
     /// The password to use for SMTP authentication.
     #[arg(short = 'W', long, help_heading = "EMAIL")]
@@ -138,8 +343,64 @@ struct Args {
     /// crash.
     #[arg(short = 'Y', long, help_heading = "EMAIL")]
     creds_file: Option<PathBuf>,
+    /// Only run the command if it hasn't already succeeded in this period,
+    /// otherwise the run is skipped, as with '--only-if'.  This is either
+    /// "day" or "hour", aligned to local time, or a duration for a rolling
+    /// period, e.g. 12h.  This is for jobs that are scheduled often, but only
+    /// need to succeed once.
+    #[arg(long, value_parser = parse_period, help_heading = "CONDITIONS")]
+    once_per: Option<Period>,
+    /// Only run the command if this shell command succeeds, otherwise the
+    /// run is skipped.  A skipped run is neither a success nor a failure, and
+    /// is only logged (with '--syslog' or '--debug') and kept in the history.
+    /// This is run like the command, with its environment, directory, user
+    /// and '--timeout'.  This can be specified multiple times.
+    /// Ex: --only-if 'mountpoint -q /mnt'
+    #[arg(long, help_heading = "CONDITIONS")]
+    only_if: Vec<String>,
+    /// Only run the command if this path exists, otherwise the run is
+    /// skipped.  This can be specified multiple times.
+    #[arg(long, help_heading = "CONDITIONS")]
+    only_if_file: Vec<PathBuf>,
+    /// Skip the run if this path exists.  This can be specified multiple
+    /// times.
+    #[arg(long, help_heading = "CONDITIONS")]
+    skip_if_file: Vec<PathBuf>,
+    /// Run this shell command when the command is about to be run.  Hooks
+    /// run with the command's environment, directory and user, and get the
+    /// details of the run in CWRAP_* environment variables and as a JSON
+    /// document on stdin.  A failed hook is logged, but doesn't change the
+    /// result of the run.
+    #[arg(long, help_heading = "HOOKS")]
+    on_start: Option<String>,
+    /// Run this shell command after each successful run
+    #[arg(long, help_heading = "HOOKS")]
+    on_success: Option<String>,
+    /// Run this shell command after each failed run, whether or not a report
+    /// is generated for it
+    #[arg(long, help_heading = "HOOKS")]
+    on_failure: Option<String>,
+    /// Run this shell command after a successful run that follows one or
+    /// more failures.  This is run after '--on-success'.
+    #[arg(long, help_heading = "HOOKS")]
+    on_recovery: Option<String>,
+    /// The time a hook can run before it's killed, e.g. 30s or 5m
+    #[arg(long, default_value = "60s", value_parser = parse_duration, help_heading = "HOOKS")]
+    hook_timeout: Duration,
+    /// Run this shell string as a step of the job instead of a single
+    /// command.  This can be specified multiple times, and the steps are run
+    /// in order, stopping at the first one that fails.
+    /// Ex: --step 'pg_dump db > db.sql' --step 'gzip db.sql'
+    #[arg(long, conflicts_with_all = ["bash_string", "cmd"], help_heading = "STEPS")]
+    step: Vec<String>,
+    /// The timeout, in seconds, for a step.  The first of these applies to
+    /// the first '--step', the second to the second, and so on.  The
+    /// '--timeout' applies to all of the steps together.
+    #[arg(long, requires = "step", help_heading = "STEPS")]
+    step_timeout: Vec<usize>,
     /// The command to run.  This can be a single string (enclosed in quotes)
     /// passed to bash if "-g" is set or the command and it's arguments.
+    #[arg(required_unless_present = "step")]
     cmd: Vec<String>,
     /// Turn on debug output
     #[arg(short = 'D', long)]
@@ -176,7 +437,21 @@ impl log::Log for GlobalLogger {
 
 /// Create a set of CLI args via the `clap` crate and return the matches
 fn get_args() -> Args {
-    return Args::parse();
+    let args = Args::parse();
+
+    // The fuzz alone mustn't be able to use up the deadline
+    if let Some(deadline) = args.deadline {
+        if args.fuzz as f64 >= deadline.as_secs_f64() {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "'--fuzz' must be less than '--deadline'",
+                )
+                .exit();
+        }
+    }
+
+    return args;
 }
 
 /// Set the global logger from the `log` crate
@@ -192,23 +467,30 @@ fn setup_logging(args: &Args) {
 }
 
 fn main() {
+    // The deadline counts from here
+    let started = Instant::now();
     let args = get_args();
     setup_logging(&args);
 
-    if let Some(p) = &args.path {
-        env::set_var("PATH", p);
-    }
-
-    let mut mgr = RunManager::new(&args);
+    let mut mgr = RunManager::new(&args, started);
     let statefile = mgr.get_statefile_clone();
 
-    // Setup signals after the manager to handle the signals and unlock in
-    // the manager
+    // Setup signals after the manager.  Once the job has started, from its
+    // conditions on, signals are forwarded to whatever is running for it and
+    // the main thread records any interrupted run, saves the state and
+    // unlocks, in that order.  Before that, there is nothing to record, so
+    // we just unlock and exit.
     let mut signals = Signals::new(&[SIGINT, SIGTERM, SIGHUP]).ok().unwrap();
     thread::spawn(move || {
-        for sig in signals.pending() {
+        for sig in signals.forever() {
+            if wsignals::forward(sig) || wsignals::is_running() {
+                debug!("Received signal {}, forwarding to the command", sig);
+                continue;
+            }
+
             debug!("Received signal {}, exiting", sig);
             statefile.unlock().ok();
+            exit(128 + sig);
         }
     });
 
@@ -218,4 +500,8 @@ fn main() {
         error!("Failed to unlock this instance: {}", e);
         exit(1);
     }
+
+    if let Some(sig) = wsignals::received() {
+        exit(128 + sig);
+    }
 }
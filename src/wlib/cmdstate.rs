@@ -1,11 +1,35 @@
 use super::errors::serialize;
+use super::exitcodes::ExitCodes;
+use super::fds;
+use super::helpers::{basename, strip_ansi};
+use super::privs::Credentials;
+use super::pty::{stdin_is_tty, Pty};
+use super::runopts::{RunOptions, StdinSource};
+use super::rusage::ResourceUsage;
+use super::signals;
 use super::statefile::StateFile;
+use super::subreaper;
 use crate::sleep_ms;
 use log::debug;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::process::{Command, Stdio};
-use std::time::{SystemTime, UNIX_EPOCH};
+use signal_hook::low_level::signal_name;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The number of runs to keep in the run history
+const HISTORY_LEN: usize = 20;
+/// The number of lines from each stream to keep from an idle command
+const LAST_OUTPUT_LINES: usize = 10;
 
 /// This will manage the overall state of running the sub-commands
 #[derive(Serialize, Deserialize)]
@@ -14,6 +38,20 @@ pub struct CmdState {
     pub bash_string: bool,
     pub num_fails: usize,
     pub failures: Vec<CmdRun>,
+    /// A short summary of the most recent runs, regardless of outcome
+    #[serde(default)]
+    pub history: Vec<RunSummary>,
+    /// The shell used to run the command if it's a bash string
+    #[serde(default)]
+    pub shell: Option<String>,
+    #[serde(default)]
+    pub strict_shell: bool,
+    /// Whether the command is a sequence of steps, each a shell string
+    #[serde(default)]
+    pub multi_step: bool,
+    /// When the last successful run finished, as a unix timestamp
+    #[serde(default)]
+    pub last_success: Option<f64>,
 }
 
 impl CmdState {
@@ -23,6 +61,11 @@ impl CmdState {
             bash_string: bash_string,
             num_fails: 0,
             failures: vec![],
+            history: vec![],
+            shell: None,
+            strict_shell: false,
+            multi_step: false,
+            last_success: None,
         };
     }
 
@@ -64,6 +107,54 @@ impl CmdState {
         self.failures = Vec::new();
     }
 
+    /// Add a run to the history, dropping the oldest entries beyond
+    /// HISTORY_LEN
+    pub fn record_history(&mut self, run: &CmdRun, status: RunStatus) {
+        self.push_history(RunSummary {
+            start_time: run.start_time,
+            run_time: run.run_time,
+            exit_code: run.exit_code,
+            status,
+            rusage: run.rusage.clone(),
+            reason: run.reason(),
+        });
+    }
+
+    /// Add a run that was skipped, as its preconditions weren't met, to the
+    /// history.  This doesn't affect the failure count.
+    pub fn record_skip(&mut self, reason: String) {
+        self.record_not_run(RunStatus::Skipped, reason);
+    }
+
+    /// Add a run that was interrupted before the command was started to the
+    /// history.  This doesn't affect the failure count either.
+    pub fn record_interrupt(&mut self, reason: String) {
+        self.record_not_run(RunStatus::Interrupted, reason);
+    }
+
+    fn record_not_run(&mut self, status: RunStatus, reason: String) {
+        self.push_history(RunSummary {
+            start_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64(),
+            run_time: 0.0,
+            exit_code: 0,
+            status,
+            rusage: None,
+            reason: Some(reason),
+        });
+    }
+
+    fn push_history(&mut self, summary: RunSummary) {
+        self.history.push(summary);
+
+        if self.history.len() > HISTORY_LEN {
+            let extra = self.history.len() - HISTORY_LEN;
+            self.history.drain(..extra);
+        }
+    }
+
     pub fn save(&self, sf: &StateFile) -> serialize::Result<()> {
         let ser_data = match serde_json::to_string(self) {
             Ok(data) => data,
@@ -84,9 +175,86 @@ impl CmdState {
         };
     }
 
+    /// The command as given.  The steps of a multi-step job are numbered,
+    /// one per line.
     pub fn cli_to_string(&self) -> String {
+        if self.multi_step {
+            let steps: Vec<String> = self
+                .cmd
+                .iter()
+                .enumerate()
+                .map(|(i, step)| format!("{}. {}", i + 1, step))
+                .collect();
+            return steps.join("\n");
+        }
+
         return self.cmd.join(" ").to_string();
     }
+
+    /// The shell to run a bash string under
+    pub fn shell_path(&self) -> String {
+        return self.shell.clone().unwrap_or("bash".to_string());
+    }
+
+    /// Return the script to pass to the shell.  In strict mode, this is
+    /// prefixed with the options to fail on errors, unset variables and
+    /// failures anywhere in a pipeline.
+    pub fn shell_script(&self) -> String {
+        return self.strict_script(self.cli_to_string());
+    }
+
+    /// Return the script to pass to the shell for a step of a multi-step job
+    pub fn step_script(&self, idx: usize) -> String {
+        return self.strict_script(self.cmd[idx].clone());
+    }
+
+    fn strict_script(&self, cli: String) -> String {
+        if !self.strict_shell {
+            return cli;
+        }
+
+        return match &basename(&self.shell_path())[..] {
+            "bash" | "zsh" | "ksh" | "mksh" => format!("set -euo pipefail\n{}", cli),
+            // A POSIX shell may or may not support pipefail, so only enable
+            // it if it's there
+            _ => format!(
+                "set -eu\n(set -o pipefail) 2>/dev/null && set -o pipefail\n{}",
+                cli
+            ),
+        };
+    }
+
+    /// A description of the shell for reports
+    pub fn shell_desc(&self) -> String {
+        let mut ret = format!("{} -c", self.shell_path());
+        if self.strict_shell {
+            ret.push_str(" (strict)");
+        }
+
+        return ret;
+    }
+}
+
+/// The outcome of a run as recorded in the history
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RunStatus {
+    Success,
+    Failure,
+    Interrupted,
+    Skipped,
+}
+
+/// A single entry in the run history
+#[derive(Serialize, Deserialize)]
+pub struct RunSummary {
+    pub start_time: f64,
+    pub run_time: f64,
+    pub exit_code: i32,
+    pub status: RunStatus,
+    pub rusage: Option<ResourceUsage>,
+    /// Why the run failed or was interrupted, if known
+    #[serde(default)]
+    pub reason: Option<String>,
 }
 
 /// This handles the state for the last command run
@@ -98,120 +266,782 @@ pub struct CmdRun {
     pub start_time: f64,
     pub run_time: f64,
     pub rust_err: Option<String>,
+    /// The signal that terminated the child, if it was killed by one
+    #[serde(default)]
+    pub signal: Option<i32>,
+    #[serde(default)]
+    pub signal_name: Option<String>,
+    #[serde(default)]
+    pub core_dumped: bool,
+    /// The resources used by the child, if it was run and reaped
+    #[serde(default)]
+    pub rusage: Option<ResourceUsage>,
+    /// A more specific explanation for a failure than the exit code alone
+    #[serde(default)]
+    pub fail_reason: Option<String>,
+    /// The environment of the child, with secrets redacted, if requested
+    #[serde(default)]
+    pub env: Option<BTreeMap<String, String>>,
+    /// The number of output lines dropped by the ignore patterns
+    #[serde(default)]
+    pub ignored_lines: usize,
+    /// Which attempt this was when retrying on failure, starting at 1
+    #[serde(default)]
+    pub attempt: usize,
+    /// The earlier, failed, attempts when retrying on failure
+    #[serde(default)]
+    pub prev_attempts: Vec<CmdRun>,
+    /// Whether the run took longer than the '--warn-after' threshold
+    #[serde(default)]
+    pub slow: bool,
+    /// Set if we killed the command for running too long or going idle
+    #[serde(default)]
+    pub killed: Option<KillReason>,
+    /// The last lines of output before the command was killed for being idle
+    #[serde(default)]
+    pub last_output: Vec<String>,
+    /// The signal cwrap received, and forwarded, while the command was running
+    #[serde(default)]
+    pub interrupted: Option<String>,
+    /// Whether the command was run under a pseudo-terminal, in which case
+    /// stdout and stderr are combined in stdout
+    #[serde(default)]
+    pub pty: bool,
+    /// Processes, as "pid: cmdline", left running by the command when it
+    /// exited, when running as a subreaper
+    #[serde(default)]
+    pub orphans: Vec<String>,
+    /// The runs of the steps of a multi-step job, in order, up to and
+    /// including the first that failed
+    #[serde(default)]
+    pub steps: Vec<CmdRun>,
+    /// The seconds slept (the fuzz) before the run, if any
+    #[serde(default)]
+    pub fuzz_secs: Option<u64>,
+    /// How the time before the '--deadline' was spent, if there is one
+    #[serde(default)]
+    pub phases: Option<PhaseTimes>,
+    /// The resource limits the command was run with, if any
+    #[serde(default)]
+    pub limits: Option<String>,
+}
+
+/// The time, in seconds, spent in each phase of a run with a deadline
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PhaseTimes {
+    pub deadline: f64,
+    pub fuzz: f64,
+    pub lock_wait: f64,
+    pub run: f64,
+}
+
+impl PhaseTimes {
+    /// Describe the deadline and how the time went, for the report
+    pub fn desc(&self) -> String {
+        return format!(
+            "{:.2} (fuzz {:.2}, lock wait {:.2}, run {:.2})",
+            self.deadline, self.fuzz, self.lock_wait, self.run,
+        );
+    }
+}
+
+/// The reason we killed the command
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum KillReason {
+    Timeout,
+    Idle,
+    /// The overall '--deadline' for the run was reached
+    Deadline,
+}
+
+/// Set up something run for the job, whether that's the command itself or
+/// one of its hooks, with the job's environment (including the PATH),
+/// working directory and user.  This returns the variables set in the
+/// environment.  It has to come after any other pre_exec() setup, as it
+/// drops the privileges that may need.
+pub fn prepare_command(
+    command: &mut Command,
+    opts: &RunOptions,
+) -> Result<Vec<(String, String)>, String> {
+    let creds = credentials(opts)?;
+
+    // Any user we switch to gets its own HOME, etc., but anything set
+    // explicitly takes precedence
+    let mut env = vec![];
+    if let Some(c) = &creds {
+        env.extend(c.env_vars());
+    }
+
+    match as_user(&creds, || opts.env.build())? {
+        Ok(vars) => env.extend(vars),
+        Err(e) => return Err(format!("Failed to set up the environment: {}", e)),
+    }
+
+    let mut chdir = None;
+    if let Some(dir) = &opts.chdir {
+        // Check this up front as the error from spawn() doesn't say what it
+        // was that didn't exist
+        if !as_user(&creds, || dir.is_dir())? {
+            return Err(format!(
+                "Working directory does not exist or is not a directory: {}",
+                dir.display()
+            ));
+        }
+
+        match &creds {
+            // Change to it once the user has been switched to, so the
+            // command can't get in to a directory the user can't
+            Some(_) => {
+                let d = match CString::new(dir.as_os_str().as_bytes()) {
+                    Ok(d) => d,
+                    Err(e) => return Err(format!("Invalid working directory: {}", e)),
+                };
+                let search = || unsafe {
+                    libc::faccessat(libc::AT_FDCWD, d.as_ptr(), libc::X_OK, libc::AT_EACCESS)
+                };
+                if as_user(&creds, search)? != 0 {
+                    return Err(format!(
+                        "Working directory is not accessible to the user: {}",
+                        dir.display()
+                    ));
+                }
+                chdir = Some(d);
+            }
+            None => {
+                command.current_dir(dir);
+            }
+        }
+    }
+
+    if opts.env.clear {
+        command.env_clear();
+    }
+    command.envs(env.iter().map(|(k, v)| (k, v)));
+
+    if let Some(creds) = creds {
+        unsafe {
+            command.pre_exec(move || creds.apply());
+        }
+    }
+
+    if let Some(dir) = chdir {
+        unsafe {
+            command.pre_exec(move || {
+                if libc::chdir(dir.as_ptr()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                return Ok(());
+            });
+        }
+    }
+
+    return Ok(env);
+}
+
+/// Wait for something other than the command that's run for the job, such as
+/// a condition or hook, to exit.  It must be in its own process group, which
+/// any signal we get is forwarded to.  If it's still running at `limit`, it's
+/// killed and this returns None.
+pub fn wait_helper(child: &mut Child, limit: Option<Instant>) -> io::Result<Option<ExitStatus>> {
+    let pgid = child.id() as libc::pid_t;
+    signals::set_child(-pgid);
+
+    let ret = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(Some(status)),
+            Ok(None) => (),
+            Err(e) => break Err(e),
+        }
+
+        if limit.map(|l| Instant::now() >= l) == Some(true) {
+            unsafe { libc::kill(-pgid, libc::SIGKILL) };
+            child.wait().ok();
+            break Ok(None);
+        }
+
+        sleep_ms!(50);
+    };
+    signals::clear_child();
+
+    return ret;
+}
+
+/// Resolve the user and group to run the command as
+fn credentials(opts: &RunOptions) -> Result<Option<Credentials>, String> {
+    return Credentials::resolve(&opts.user, &opts.group)
+        .map_err(|e| format!("Failed to switch user: {}", e));
+}
+
+/// Run `f` as the user the command is run as, if that's not us, so that it
+/// can only open what the command could
+fn as_user<T, F: FnOnce() -> T>(creds: &Option<Credentials>, f: F) -> Result<T, String> {
+    return match creds {
+        Some(c) => c
+            .as_user(f)
+            .map_err(|e| format!("Failed to switch user: {}", e)),
+        None => Ok(f()),
+    };
+}
+
+/// Collects the output from one of the child's pipes in a background thread,
+/// recording when output was last seen
+struct PipeReader {
+    buf: Arc<Mutex<Vec<u8>>>,
+    handle: JoinHandle<()>,
+}
+
+impl PipeReader {
+    fn spawn<R: Read + Send + 'static>(pipe: Option<R>, activity: Arc<Mutex<Instant>>) -> Self {
+        let buf = Arc::new(Mutex::new(vec![]));
+        let t_buf = buf.clone();
+        let handle = thread::spawn(move || {
+            let mut p = match pipe {
+                Some(p) => p,
+                None => return,
+            };
+
+            let mut chunk = [0u8; 8192];
+            loop {
+                match p.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        t_buf.lock().unwrap().extend_from_slice(&chunk[..n]);
+                        *activity.lock().unwrap() = Instant::now();
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    // This is how the end of the output from a pty looks
+                    Err(e) if e.raw_os_error() == Some(libc::EIO) => break,
+                    Err(e) => {
+                        debug!("Error reading output from the child: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        return Self { buf, handle };
+    }
+
+    /// Wait for the pipe to be closed and return everything read from it
+    fn finish(self) -> Vec<u8> {
+        self.handle.join().ok();
+        return self.buf.lock().unwrap().clone();
+    }
+
+    /// Return what has been read so far, without waiting for the pipe to close
+    fn snapshot(&self) -> Vec<u8> {
+        return self.buf.lock().unwrap().clone();
+    }
 }
 
 impl CmdRun {
     /// Do a run of a command and return a CmdRun struct as the result
-    pub fn run(cmd_state: &CmdState, bash_string: bool, timeout: usize) -> Self {
-        let start = SystemTime::now();
-
+    pub fn run(cmd_state: &CmdState, opts: &RunOptions) -> Self {
         debug!(
             "Spawning the child process for {}",
             cmd_state.cli_to_string()
         );
-        let mut proc;
-
-        if bash_string {
-            // We have to run this as a string under bash instead
-            proc = match Command::new("bash")
-                .args(&["-c".to_string(), cmd_state.cli_to_string()])
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()
-            {
-                Ok(child) => child,
-                Err(e) => {
-                    return CmdRun::rust_err(format!("Failed to spawn child: {}", e));
-                }
-            };
+
+        let mut command;
+        if cmd_state.bash_string {
+            // We have to run this as a string under the shell instead
+            command = Command::new(cmd_state.shell_path());
+            command.args(&["-c".to_string(), cmd_state.shell_script()]);
         } else {
-            proc = match Command::new(&cmd_state.cmd[0])
-                .args(&cmd_state.cmd[1..])
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()
-            {
-                Ok(child) => child,
-                Err(e) => {
-                    return CmdRun::rust_err(format!("Failed to spawn child: {}", e));
+            command = Command::new(&cmd_state.cmd[0]);
+            command.args(&cmd_state.cmd[1..]);
+        }
+
+        return Self::run_command(command, opts, opts.timeout, None);
+    }
+
+    /// Run a single step of a multi-step job, with that step's timeout.  The
+    /// '--timeout' covers the whole job, which started at `job_started`.
+    pub fn run_step(
+        cmd_state: &CmdState,
+        idx: usize,
+        opts: &RunOptions,
+        job_started: Instant,
+    ) -> Self {
+        debug!(
+            "Spawning the child process for step {}: {}",
+            idx + 1,
+            cmd_state.cmd[idx]
+        );
+
+        let mut command = Command::new(cmd_state.shell_path());
+        command.args(&["-c".to_string(), cmd_state.step_script(idx)]);
+
+        let job_timeout_at = match opts.timeout {
+            0 => None,
+            t => Some(job_started + Duration::from_secs(t as u64)),
+        };
+        if job_timeout_at.map(|t| Instant::now() >= t) == Some(true) {
+            return CmdRun::rust_err(format!(
+                "Job reached timeout of {} secs before step {} was run",
+                opts.timeout,
+                idx + 1
+            ));
+        }
+
+        return Self::run_command(command, opts, opts.step_timeout(idx), job_timeout_at);
+    }
+
+    /// Combine the runs of the steps of a job, in order, into the run for
+    /// the job as a whole.  The outcome is that of the last step run, which
+    /// is the one that failed if any did.
+    pub fn from_steps(steps: Vec<CmdRun>) -> Self {
+        let last = match steps.last() {
+            Some(l) => l,
+            None => return CmdRun::rust_err("The job has no steps".to_string()),
+        };
+
+        let mut ret = Self {
+            exit_code: last.exit_code,
+            stdout: String::new(),
+            stderr: String::new(),
+            start_time: steps[0].start_time,
+            run_time: 0.0,
+            rust_err: last.rust_err.clone(),
+            signal: last.signal,
+            signal_name: last.signal_name.clone(),
+            core_dumped: last.core_dumped,
+            rusage: None,
+            fail_reason: last.fail_reason.clone(),
+            env: steps[0].env.clone(),
+            ignored_lines: 0,
+            attempt: 1,
+            prev_attempts: vec![],
+            slow: false,
+            killed: last.killed,
+            last_output: last.last_output.clone(),
+            interrupted: last.interrupted.clone(),
+            pty: last.pty,
+            orphans: vec![],
+            steps: vec![],
+            fuzz_secs: None,
+            phases: None,
+            limits: last.limits.clone(),
+        };
+
+        for step in &steps {
+            ret.stdout.push_str(&step.stdout);
+            ret.stderr.push_str(&step.stderr);
+            ret.run_time += step.run_time;
+            ret.ignored_lines += step.ignored_lines;
+            ret.orphans.extend(step.orphans.iter().cloned());
+            if let Some(usage) = &step.rusage {
+                match &mut ret.rusage {
+                    Some(total) => total.add(usage),
+                    None => ret.rusage = Some(usage.clone()),
                 }
-            };
+            }
         }
+        ret.steps = steps;
 
-        debug!("Child started with pid: {}", proc.id());
+        return ret;
+    }
+
+    /// Run the command, killing it if it goes over the timeout, in seconds,
+    /// or the timeout of the job it's a step of
+    fn run_command(
+        mut command: Command,
+        opts: &RunOptions,
+        timeout_secs: usize,
+        job_timeout_at: Option<Instant>,
+    ) -> Self {
+        if opts.deadline.map(|d| Instant::now() >= d) == Some(true) {
+            return CmdRun::rust_err(
+                "The deadline was reached before the command was run".to_string(),
+            );
+        }
 
-        // Convert to millis
-        let timeout = timeout * 1000;
-
-        let mut run_time = 0;
-        if timeout > 0 {
-            // Need to handle timeouts here with try_wait on the proc
-            while run_time < timeout {
-                match &proc.try_wait() {
-                    Ok(Some(_)) => break,
-                    Ok(None) => {
-                        run_time += 100;
-                        sleep_ms!(100);
+        let start = SystemTime::now();
+        // Never leave the command reading from whatever cwrap's stdin happens
+        // to be unless asked to
+        match &opts.stdin {
+            StdinSource::Null => {
+                command.stdin(Stdio::null());
+            }
+            StdinSource::Inherit => {
+                command.stdin(Stdio::inherit());
+            }
+            StdinSource::File(path) => {
+                let opened = credentials(opts).and_then(|c| as_user(&c, || File::open(path)));
+                match opened {
+                    Ok(Ok(f)) => {
+                        command.stdin(f);
                     }
-                    Err(e) => {
-                        return CmdRun::rust_err(format!("Failure to spawn child: {}", e));
+                    Ok(Err(e)) => {
+                        return CmdRun::rust_err(format!(
+                            "Failed to open stdin file {}: {}",
+                            path.display(),
+                            e
+                        ));
                     }
+                    Err(e) => return CmdRun::rust_err(e),
                 }
             }
         }
 
-        // Check to see if we went over time
-        if timeout > 0 && run_time >= timeout {
-            match &proc.try_wait() {
-                Ok(None) => {
-                    debug!("Timeout exceeded, killing the subprocess");
-
-                    match proc.kill() {
-                        Ok(_) => {
-                            return Self {
-                                exit_code: -1,
-                                stdout: String::new(),
-                                stderr: String::new(),
-                                start_time: start.duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
-                                run_time: SystemTime::now()
-                                    .duration_since(start)
-                                    .unwrap()
-                                    .as_secs_f64(),
-                                rust_err: Some(format!(
-                                    "Command reached timeout of {} secs",
-                                    timeout / 1000,
-                                )),
-                            }
-                        }
-                        Err(e) => {
-                            return CmdRun::rust_err(format!("Failed to kill subprocess! {}", e))
-                        }
-                    }
+        // A command reading from our terminal has to stay in our process
+        // group, which the terminal has in the foreground, or it's stopped
+        // with SIGTTIN as soon as it reads.  Otherwise, it gets a group of
+        // its own so that it, and anything it starts, can be killed together.
+        let own_group = opts.pty || !(opts.stdin == StdinSource::Inherit && stdin_is_tty());
+
+        let mut pty_master = None;
+        if opts.pty {
+            let parts = Pty::open().and_then(|pty| pty.into_parts());
+            let (master, stdout, stderr) = match parts {
+                Ok(p) => p,
+                Err(e) => {
+                    return CmdRun::rust_err(format!("Failed to open a pseudo-terminal: {}", e));
                 }
-                _ => (),
+            };
+            command.stdout(stdout).stderr(stderr);
+            pty_master = Some(master);
+        } else {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+            if own_group {
+                command.process_group(0);
+            }
+        }
+
+        // The pre_exec() hooks run in the child, between the fork and the
+        // exec, in the order they're added.  Only async-signal-safe calls
+        // are allowed there, so they must not allocate, lock or log, and
+        // anything they need is prepared here beforehand.
+        if opts.pty {
+            // The new session also puts the command in its own process group
+            unsafe {
+                command.pre_exec(Pty::make_controlling);
+            }
+        }
+
+        if !opts.limits.is_empty() {
+            let limits = opts.limits.clone();
+            unsafe {
+                command.pre_exec(move || limits.apply());
+            }
+        }
+
+        if !opts.priority.is_empty() {
+            let priority = opts.priority.clone();
+            unsafe {
+                command.pre_exec(move || priority.apply());
+            }
+        }
+
+        if let Some(mask) = opts.umask {
+            unsafe {
+                command.pre_exec(move || {
+                    libc::umask(mask as libc::mode_t);
+                    return Ok(());
+                });
+            }
+        }
+
+        for fd in opts.pass_fds.iter().copied() {
+            if !fds::is_open(fd) {
+                return CmdRun::rust_err(format!(
+                    "File descriptor {} passed with --pass-fd is not open",
+                    fd
+                ));
+            }
+            unsafe {
+                command.pre_exec(move || fds::inherit(fd));
+            }
+        }
+
+        // This has to be the last thing set up in the child as it drops the
+        // privileges the other setup may need
+        let env = match prepare_command(&mut command, opts) {
+            Ok(e) => e,
+            Err(e) => return CmdRun::rust_err(e),
+        };
+
+        if opts.subreaper {
+            if let Err(e) = subreaper::enable() {
+                return CmdRun::rust_err(format!("Failed to become a subreaper: {}", e));
             }
         }
 
-        let output = match proc.wait_with_output() {
-            Ok(out) => out,
+        // Make sure nothing leaked to us, including the lock, ends up in the
+        // command
+        if let Err(e) = fds::close_on_exec(&opts.pass_fds) {
+            return CmdRun::rust_err(format!("Failed to close inherited descriptors: {}", e));
+        }
+
+        let mut proc = match command.spawn() {
+            Ok(child) => child,
             Err(e) => {
+                return CmdRun::rust_err(format!("Failed to spawn child: {}", e));
+            }
+        };
+        // Drop our handles to the terminal, if any, so reading from the pty
+        // ends when the command exits
+        drop(command);
+
+        debug!("Child started with pid: {}", proc.id());
+
+        // Drain the output pipes in the background so the child can't block
+        // on a full pipe while we wait on it
+        let activity = Arc::new(Mutex::new(Instant::now()));
+        let stdout_r = match pty_master {
+            Some(master) => PipeReader::spawn(Some(master), activity.clone()),
+            None => PipeReader::spawn(proc.stdout.take(), activity.clone()),
+        };
+        let stderr_r = PipeReader::spawn(proc.stderr.take(), activity.clone());
+        let pid = proc.id() as libc::pid_t;
+        let target = if own_group { -pid } else { pid };
+        signals::set_child(target);
+
+        let started = Instant::now();
+        let own_timeout_at = match timeout_secs {
+            0 => None,
+            _ => Some(started + Duration::from_secs(timeout_secs as u64)),
+        };
+        // Whichever of the two timeouts comes first is the one that applies
+        let timeout_at = match (own_timeout_at, job_timeout_at) {
+            (Some(o), Some(j)) => Some(o.min(j)),
+            (o, j) => o.or(j),
+        };
+        let job_timeout_first = job_timeout_at.is_some() && timeout_at == job_timeout_at;
+        let poll = timeout_at.is_some() || opts.deadline.is_some() || opts.idle_timeout.is_some();
+        // Which limit, if any, the command has run into
+        let expired = || {
+            let now = Instant::now();
+            if timeout_at.map(|t| now >= t) == Some(true) {
+                return Some(KillReason::Timeout);
+            } else if opts.deadline.map(|d| now >= d) == Some(true) {
+                return Some(KillReason::Deadline);
+            }
+
+            return None;
+        };
+
+        let mut killed = None;
+        let mut status: libc::c_int = 0;
+        let mut usage: libc::rusage = unsafe { mem::zeroed() };
+        loop {
+            // We reap the child ourselves with wait4() so that we also get
+            // its resource usage
+            let flags = if poll { libc::WNOHANG } else { 0 };
+            let ret = unsafe { libc::wait4(pid, &mut status, flags, &mut usage) };
+            if ret == pid {
+                break;
+            } else if ret < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                signals::clear_child();
                 return CmdRun::rust_err(format!("Failure running child: {}", e));
             }
+
+            // Check to see if we went over time, or if the command has gone
+            // quiet for too long
+            killed = expired();
+            if killed.is_some() {
+                debug!("Timeout or deadline exceeded, killing the subprocess");
+            } else if let Some(idle) = opts.idle_timeout {
+                if activity.lock().unwrap().elapsed() >= idle {
+                    debug!("Idle timeout exceeded, killing the subprocess");
+                    killed = Some(KillReason::Idle);
+                }
+            }
+
+            if killed.is_some() {
+                // Kill the whole process group, if it has one, so nothing is
+                // left behind holding on to the output pipes
+                if unsafe { libc::kill(target, libc::SIGKILL) } != 0 {
+                    signals::clear_child();
+                    return CmdRun::rust_err(format!(
+                        "Failed to kill subprocess! {}",
+                        io::Error::last_os_error()
+                    ));
+                }
+
+                // Reap the killed child so we still get its usage
+                while unsafe { libc::wait4(pid, &mut status, 0, &mut usage) } < 0 {
+                    if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                        break;
+                    }
+                }
+                break;
+            }
+
+            sleep_ms!(100);
+        }
+
+        // Anything the command left behind has been reparented to us.  These
+        // have to be dealt with before reading the rest of the output, as
+        // they may still be holding the pipes open.
+        let mut orphans = vec![];
+        if opts.subreaper {
+            subreaper::reap();
+            orphans = subreaper::descendants().iter().map(|o| o.desc()).collect();
+            if !orphans.is_empty() {
+                debug!("Command left {} processes running", orphans.len());
+                let limit = match (timeout_at, opts.deadline) {
+                    (Some(t), Some(d)) => Some(t.min(d)),
+                    (t, d) => t.or(d),
+                };
+                if killed.is_some() || opts.kill_orphans {
+                    subreaper::kill_all(opts.deadline);
+                } else if !subreaper::wait_all(limit, || signals::received().is_some()) {
+                    if signals::received().is_none() {
+                        debug!("Timeout exceeded waiting for orphans, killing them");
+                        killed = expired();
+                    }
+                    subreaper::kill_all(opts.deadline);
+                }
+            }
+        }
+        signals::clear_child();
+
+        // If we killed the command, something outside of its process group
+        // may still have the pipes open, so just take what we have so far
+        let (stdout, stderr) = match killed {
+            Some(_) => (stdout_r.snapshot(), stderr_r.snapshot()),
+            None => (stdout_r.finish(), stderr_r.finish()),
         };
 
         let total_run_time = SystemTime::now().duration_since(start).unwrap();
+        let exit_status = ExitStatus::from_raw(status);
+        let signal = exit_status.signal();
 
-        return Self {
-            exit_code: output.status.code().unwrap_or(-1),
-            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
-            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        let mut stdout = String::from_utf8_lossy(&stdout).to_string();
+        if opts.pty {
+            // The terminal translates newlines to CRLF
+            stdout = stdout.replace("\r\n", "\n");
+        }
+        let mut stderr = String::from_utf8_lossy(&stderr).to_string();
+        if opts.strip_ansi {
+            stdout = strip_ansi(&stdout);
+            stderr = strip_ansi(&stderr);
+        }
+
+        let mut ret = Self {
+            exit_code: exit_status.code().unwrap_or(-1),
+            stdout: stdout,
+            stderr: stderr,
             start_time: start.duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
             run_time: total_run_time.as_secs_f64(),
             rust_err: None,
+            signal,
+            signal_name: signal.map(Self::sig_to_name),
+            core_dumped: exit_status.core_dumped(),
+            rusage: Some(ResourceUsage::from_rusage(&usage)),
+            fail_reason: None,
+            env: None,
+            ignored_lines: 0,
+            attempt: 1,
+            prev_attempts: vec![],
+            slow: false,
+            killed: killed,
+            last_output: vec![],
+            interrupted: signals::received().map(Self::sig_to_name),
+            pty: opts.pty,
+            orphans: orphans,
+            steps: vec![],
+            fuzz_secs: None,
+            phases: None,
+            limits: opts.limits.desc(),
+        };
+
+        match killed {
+            Some(KillReason::Timeout) if job_timeout_first => {
+                ret.rust_err = Some(format!("Job reached timeout of {} secs", opts.timeout));
+            }
+            Some(KillReason::Timeout) => {
+                ret.rust_err = Some(format!("Command reached timeout of {} secs", timeout_secs));
+            }
+            Some(KillReason::Deadline) => {
+                ret.rust_err = Some("Command was still running at the deadline".to_string());
+            }
+            Some(KillReason::Idle) => {
+                ret.rust_err = Some(format!(
+                    "Command produced no output for {:.0} secs (idle timeout)",
+                    opts.idle_timeout.unwrap().as_secs_f64()
+                ));
+                ret.last_output = ret.output_tail(LAST_OUTPUT_LINES);
+            }
+            None => ret.fail_reason = opts.limits.violation(&ret),
+        }
+
+        if opts.env.report {
+            ret.env = Some(opts.env.effective(&env));
+        }
+
+        return ret;
+    }
+
+    /// Return the last `n` lines each of stdout and stderr, tagged with the
+    /// stream they came from
+    pub fn output_tail(&self, n: usize) -> Vec<String> {
+        let mut ret = vec![];
+        for (name, text) in [("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            let lines: Vec<&str> = text.lines().collect();
+            let skip = lines.len().saturating_sub(n);
+            for line in &lines[skip..] {
+                ret.push(format!("[{}] {}", name, line));
+            }
+        }
+
+        return ret;
+    }
+
+    /// A short explanation of why the run failed or was interrupted
+    pub fn reason(&self) -> Option<String> {
+        if let Some(sig) = &self.interrupted {
+            return Some(format!("Interrupted by {}", sig));
+        }
+
+        return self.rust_err.clone().or(self.fail_reason.clone());
+    }
+
+    /// Whether this run counts as a success, given the set of exit codes
+    /// that are considered successful
+    pub fn is_success(&self, ok_codes: &ExitCodes) -> bool {
+        return self.rust_err.is_none()
+            && self.interrupted.is_none()
+            && self.signal.is_none()
+            && self.fail_reason.is_none()
+            && ok_codes.contains(self.exit_code);
+    }
+
+    /// Return a human readable description of how the command exited, which
+    /// is either the exit code or the signal that killed it
+    pub fn exit_desc(&self) -> String {
+        if self.signal.is_none() {
+            return format!("{}", self.exit_code);
+        }
+
+        let name = match &self.signal_name {
+            Some(n) => n.clone(),
+            None => Self::sig_to_name(self.signal.unwrap()),
+        };
+
+        let mut ret = format!("Killed by {}", name);
+        if self.core_dumped {
+            ret.push_str(" (core dumped)");
+        }
+
+        return ret;
+    }
+
+    /// Convert a signal number to its name, falling back to the number itself
+    /// for anything we don't know about
+    pub fn sig_to_name(sig: i32) -> String {
+        return match signal_name(sig) {
+            Some(name) => name.to_string(),
+            None => format!("signal {}", sig),
         };
     }
 
-    fn rust_err(err_msg: String) -> Self {
+    pub fn rust_err(err_msg: String) -> Self {
         return Self {
             exit_code: 0,
             stdout: String::new(),
@@ -219,6 +1049,230 @@ impl CmdRun {
             start_time: 0.0,
             run_time: 0.0,
             rust_err: Some(err_msg),
+            signal: None,
+            signal_name: None,
+            core_dumped: false,
+            rusage: None,
+            fail_reason: None,
+            env: None,
+            ignored_lines: 0,
+            attempt: 1,
+            prev_attempts: vec![],
+            slow: false,
+            killed: None,
+            last_output: vec![],
+            interrupted: None,
+            pty: false,
+            orphans: vec![],
+            steps: vec![],
+            fuzz_secs: None,
+            phases: None,
+            limits: None,
         };
     }
+
+    /// Build a run that exited with the code, or was killed by the signal,
+    /// for tests
+    #[cfg(test)]
+    pub fn for_test(exit_code: i32, signal: Option<i32>) -> Self {
+        let mut ret = Self::rust_err(String::new());
+        ret.rust_err = None;
+        ret.exit_code = exit_code;
+        ret.signal = signal;
+        ret.signal_name = signal.map(Self::sig_to_name);
+
+        return ret;
+    }
+
+    #[cfg(test)]
+    pub fn with_output(mut self, stdout: &str, stderr: &str) -> Self {
+        self.stdout = stdout.to_string();
+        self.stderr = stderr.to_string();
+
+        return self;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Args;
+    use clap::Parser;
+
+    #[test]
+    fn test_exit_desc() {
+        assert_eq!("0", CmdRun::for_test(0, None).exit_desc());
+        assert_eq!("2", CmdRun::for_test(2, None).exit_desc());
+        assert_eq!(
+            "Killed by SIGKILL",
+            CmdRun::for_test(-1, Some(9)).exit_desc()
+        );
+
+        let mut run = CmdRun::for_test(-1, Some(11));
+        run.core_dumped = true;
+        assert_eq!("Killed by SIGSEGV (core dumped)", run.exit_desc());
+    }
+
+    #[test]
+    fn test_is_success() {
+        let ok = ExitCodes::parse("0,24").unwrap();
+        assert!(CmdRun::for_test(0, None).is_success(&ok));
+        assert!(CmdRun::for_test(24, None).is_success(&ok));
+        assert!(!CmdRun::for_test(1, None).is_success(&ok));
+        assert!(!CmdRun::for_test(-1, Some(9)).is_success(&ok));
+
+        let mut run = CmdRun::for_test(0, None);
+        run.rust_err = Some("Command reached timeout of 1 secs".to_string());
+        assert!(!run.is_success(&ok));
+    }
+
+    #[test]
+    fn test_output_tail() {
+        let mut run = CmdRun::for_test(0, None);
+        run.stdout = "1\n2\n3\n".to_string();
+        run.stderr = "err".to_string();
+
+        assert_eq!(
+            vec!["[stdout] 2", "[stdout] 3", "[stderr] err"],
+            run.output_tail(2)
+        );
+    }
+
+    #[test]
+    fn test_record_history() {
+        let mut state = CmdState::new(&vec!["true".to_string()], false);
+        for i in 0..(HISTORY_LEN + 5) {
+            let mut run = CmdRun::for_test(0, None);
+            run.start_time = i as f64;
+            state.record_history(&run, RunStatus::Success);
+        }
+
+        assert_eq!(HISTORY_LEN, state.history.len());
+        assert_eq!(5.0, state.history[0].start_time);
+        assert_eq!(
+            (HISTORY_LEN + 4) as f64,
+            state.history[HISTORY_LEN - 1].start_time
+        );
+
+        state.num_fails = 2;
+        state.record_skip("/mnt is not mounted".to_string());
+        assert_eq!(HISTORY_LEN, state.history.len());
+        assert_eq!(RunStatus::Skipped, state.history[HISTORY_LEN - 1].status);
+        assert_eq!(2, state.num_fails);
+    }
+
+    #[test]
+    fn test_shell_script() {
+        let mut state = CmdState::new(&vec!["false | cat".to_string()], true);
+        assert_eq!("bash", state.shell_path());
+        assert_eq!("false | cat", state.shell_script());
+        assert_eq!("bash -c", state.shell_desc());
+
+        state.strict_shell = true;
+        assert_eq!("set -euo pipefail\nfalse | cat", state.shell_script());
+        assert_eq!("bash -c (strict)", state.shell_desc());
+
+        state.shell = Some("/bin/sh".to_string());
+        assert!(state.shell_script().starts_with("set -eu\n"));
+        assert!(state.shell_script().ends_with("\nfalse | cat"));
+    }
+
+    #[test]
+    fn test_steps() {
+        let mut state = CmdState::new(&vec!["make".to_string(), "make install".to_string()], true);
+        state.multi_step = true;
+        state.strict_shell = true;
+        assert_eq!("1. make\n2. make install", state.cli_to_string());
+        assert_eq!("set -euo pipefail\nmake install", state.step_script(1));
+
+        let mut first = CmdRun::for_test(0, None);
+        first.stdout = "built\n".to_string();
+        first.run_time = 1.5;
+        let mut second = CmdRun::for_test(2, None);
+        second.stdout = "failed\n".to_string();
+        second.run_time = 0.5;
+
+        let run = CmdRun::from_steps(vec![first, second]);
+        assert_eq!(2, run.exit_code);
+        assert_eq!(2.0, run.run_time);
+        assert_eq!("built\nfailed\n", run.stdout);
+        assert_eq!(2, run.steps.len());
+        assert!(!run.is_success(&ExitCodes::default()));
+    }
+
+    #[test]
+    fn test_phases_desc() {
+        let phases = PhaseTimes {
+            deadline: 240.0,
+            fuzz: 12.5,
+            lock_wait: 0.004,
+            run: 100.0,
+        };
+        assert_eq!(
+            "240.00 (fuzz 12.50, lock wait 0.00, run 100.00)",
+            phases.desc()
+        );
+    }
+
+    #[test]
+    fn test_deadline_kill() {
+        let args = Args::parse_from(["cwrap", "--deadline", "0.3s", "sleep", "5"]);
+        let opts = RunOptions::from_args(&args, Instant::now());
+        let state = CmdState::new(&args.cmd, false);
+
+        let run = CmdRun::run(&state, &opts);
+        assert_eq!(Some(KillReason::Deadline), run.killed);
+        assert_eq!(Some(libc::SIGKILL), run.signal);
+        assert!(run.run_time < 2.0);
+        assert_eq!(
+            Some("Command was still running at the deadline".to_string()),
+            run.rust_err
+        );
+
+        // Nothing is started once the deadline has passed
+        let run = CmdRun::run(&state, &opts);
+        assert_eq!(None, run.killed);
+        assert_eq!(
+            Some("The deadline was reached before the command was run".to_string()),
+            run.rust_err
+        );
+    }
+
+    #[test]
+    fn test_step_job_timeout() {
+        let args = Args::parse_from(["cwrap", "-t", "1", "--step", "sleep 5"]);
+        let opts = RunOptions::from_args(&args, Instant::now());
+        let mut state = CmdState::new(&args.step, true);
+        state.multi_step = true;
+
+        // The step has no timeout of its own, so it gets what's left of the
+        // job's timeout
+        let job_started = Instant::now() - Duration::from_millis(700);
+        let run = CmdRun::run_step(&state, 0, &opts, job_started);
+        assert_eq!(Some(KillReason::Timeout), run.killed);
+        assert!(run.run_time < 0.9);
+        assert_eq!(
+            Some("Job reached timeout of 1 secs".to_string()),
+            run.rust_err
+        );
+
+        let job_started = Instant::now() - Duration::from_secs(2);
+        let run = CmdRun::run_step(&state, 0, &opts, job_started);
+        assert_eq!(None, run.killed);
+        assert_eq!(
+            Some("Job reached timeout of 1 secs before step 1 was run".to_string()),
+            run.rust_err
+        );
+    }
+
+    #[test]
+    fn test_load_old_run() {
+        // Runs serialized before the signal fields existed must still load
+        let data = r#"{"exit_code":1,"stdout":"","stderr":"","start_time":0.0,
+            "run_time":0.0,"rust_err":null}"#;
+        let run: CmdRun = serde_json::from_str(data).unwrap();
+
+        assert_eq!(None, run.signal);
+        assert!(!run.core_dumped);
+    }
 }
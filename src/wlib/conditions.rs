@@ -0,0 +1,128 @@
+use super::cmdstate::{prepare_command, wait_helper};
+use super::runopts::RunOptions;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// The preconditions that must hold for the command to be run.  These are
+/// checked in order, and the first that fails skips the run.
+#[derive(Clone, Default)]
+pub struct Conditions {
+    /// Shell commands that must all succeed
+    pub only_if: Vec<String>,
+    /// Paths that must all exist
+    pub only_if_file: Vec<PathBuf>,
+    /// Paths that must not exist
+    pub skip_if_file: Vec<PathBuf>,
+}
+
+impl Conditions {
+    /// Check the conditions, returning the reason to skip the run if any of
+    /// them fail.  The commands are run under the given shell, set up the
+    /// same way as the command, and each gets the command's '--timeout'.
+    /// This returns an error if a command couldn't be run at all, as that
+    /// says nothing about whether the job should be.
+    pub fn check(&self, shell: &str, opts: &RunOptions) -> Result<Option<String>, String> {
+        for path in &self.skip_if_file {
+            if path.exists() {
+                return Ok(Some(format!("{} exists", path.display())));
+            }
+        }
+
+        for path in &self.only_if_file {
+            if !path.exists() {
+                return Ok(Some(format!("{} does not exist", path.display())));
+            }
+        }
+
+        for cmd in &self.only_if {
+            let mut command = Command::new(shell);
+            if let Err(e) = prepare_command(&mut command, opts) {
+                return Err(format!("The condition `{}` could not be run: {}", cmd, e));
+            }
+            let child = command
+                .args(["-c", cmd])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .process_group(0)
+                .spawn();
+
+            let status = child.and_then(|mut c| wait_helper(&mut c, opts.limit()));
+            match status {
+                Ok(Some(s)) if s.success() => (),
+                Ok(Some(s)) => return Ok(Some(format!("`{}` failed ({})", cmd, s))),
+                Ok(None) => return Ok(Some(format!("`{}` timed out", cmd))),
+                Err(e) => {
+                    return Err(format!("The condition `{}` could not be run: {}", cmd, e));
+                }
+            }
+        }
+
+        return Ok(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_check() {
+        let opts = RunOptions::for_test(&[]);
+        let mut conds = Conditions::default();
+        assert_eq!(Ok(None), conds.check("sh", &opts));
+
+        conds.only_if_file = vec![PathBuf::from("/")];
+        conds.only_if = vec!["true".to_string()];
+        assert_eq!(Ok(None), conds.check("sh", &opts));
+
+        conds.only_if.push("exit 3".to_string());
+        assert_eq!(
+            Ok(Some("`exit 3` failed (exit status: 3)".to_string())),
+            conds.check("sh", &opts)
+        );
+
+        conds.skip_if_file = vec![PathBuf::from("/")];
+        assert_eq!(Ok(Some("/ exists".to_string())), conds.check("sh", &opts));
+
+        conds.skip_if_file.clear();
+        conds.only_if_file.push(PathBuf::from("/nonexistent/cwrap"));
+        assert_eq!(
+            Ok(Some("/nonexistent/cwrap does not exist".to_string())),
+            conds.check("sh", &opts)
+        );
+    }
+
+    #[test]
+    fn test_check_like_command() {
+        let conds = Conditions {
+            only_if: vec!["[ \"$FOO\" = bar ]".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            Ok(None),
+            conds.check("sh", &RunOptions::for_test(&["--env", "FOO=bar"]))
+        );
+
+        // A condition that can't be run at all is an error, not a skip
+        assert!(conds
+            .check("/nonexistent/sh", &RunOptions::for_test(&[]))
+            .is_err());
+        assert!(conds
+            .check("sh", &RunOptions::for_test(&["--chdir", "/nonexistent"]))
+            .is_err());
+
+        let conds = Conditions {
+            only_if: vec!["sleep 5".to_string()],
+            ..Default::default()
+        };
+        let started = Instant::now();
+        assert_eq!(
+            Ok(Some("`sleep 5` timed out".to_string())),
+            conds.check("sh", &RunOptions::for_test(&["-t", "1"]))
+        );
+        assert!(started.elapsed().as_secs_f64() < 2.0);
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Any variable with one of these in its name will have its value hidden
+/// in reports
+const SECRET_MARKERS: [&str; 7] = [
+    "PASS",
+    "SECRET",
+    "TOKEN",
+    "KEY",
+    "CREDENTIAL",
+    "AUTH",
+    "PRIVATE",
+];
+
+/// The options for building the environment of the child
+#[derive(Clone, Default)]
+pub struct EnvOptions {
+    /// Start the child with an empty environment
+    pub clear: bool,
+    /// Files, in dotenv format, to load variables from
+    pub files: Vec<PathBuf>,
+    /// Variables set explicitly, which override the files
+    pub vars: Vec<(String, String)>,
+    /// The PATH to use, which overrides everything else
+    pub path: Option<String>,
+    /// Whether to include the environment in failure reports
+    pub report: bool,
+}
+
+impl EnvOptions {
+    /// Build the list of variables to set in the child, in the order they
+    /// should be applied.  If `clear` is set, these are the only variables
+    /// the child will have.
+    pub fn build(&self) -> Result<Vec<(String, String)>, String> {
+        let mut ret = vec![];
+        for path in &self.files {
+            let contents = match fs::read_to_string(path) {
+                Ok(c) => c,
+                Err(e) => return Err(format!("Failed to read env file {}: {}", path.display(), e)),
+            };
+
+            match parse_env_file(&contents) {
+                Ok(vars) => ret.extend(vars),
+                Err(e) => return Err(format!("Invalid env file {}: {}", path.display(), e)),
+            }
+        }
+
+        ret.extend(self.vars.iter().cloned());
+
+        if let Some(p) = &self.path {
+            ret.push(("PATH".to_string(), p.clone()));
+        }
+
+        return Ok(ret);
+    }
+
+    /// Return the environment the child will actually see, given the
+    /// variables that will be set in it, with secrets redacted
+    pub fn effective(&self, vars: &[(String, String)]) -> BTreeMap<String, String> {
+        let mut ret = BTreeMap::new();
+        if !self.clear {
+            for (k, v) in env::vars() {
+                ret.insert(k, v);
+            }
+        }
+
+        for (k, v) in vars {
+            ret.insert(k.clone(), v.clone());
+        }
+
+        for (k, v) in ret.iter_mut() {
+            if is_secret(k) {
+                *v = "<redacted>".to_string();
+            }
+        }
+
+        return ret;
+    }
+}
+
+/// Check whether the variable name looks like it holds a secret
+pub fn is_secret(key: &str) -> bool {
+    let key = key.to_uppercase();
+    return SECRET_MARKERS.iter().any(|m| key.contains(m));
+}
+
+fn valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    return match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    };
+}
+
+/// Parse a KEY=VALUE pair, where the key is letters, digits and underscores
+/// and doesn't start with a digit.  The value can be empty.
+pub fn parse_env_var(val: &str) -> Result<(String, String), String> {
+    return match val.split_once('=') {
+        Some((k, v)) if valid_key(k) => Ok((k.to_string(), v.to_string())),
+        _ => Err(format!(
+            "Invalid environment variable, expected KEY=VALUE: {}",
+            val
+        )),
+    };
+}
+
+/// Parse the contents of a dotenv style file.  This supports comments,
+/// blank lines, an optional leading "export " and single or double quoted
+/// values.
+pub fn parse_env_file(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut ret = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let mut line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix("export ") {
+            line = rest.trim_start();
+        }
+
+        let (key, val) = match line.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => return Err(format!("line {}: expected KEY=VALUE", i + 1)),
+        };
+
+        if !valid_key(key) {
+            return Err(format!("line {}: invalid variable name: {}", i + 1, key));
+        }
+
+        let val = if val.len() >= 2 && val.starts_with('\'') && val.ends_with('\'') {
+            // Single quoted values are taken literally
+            val[1..val.len() - 1].to_string()
+        } else if val.len() >= 2 && val.starts_with('"') && val.ends_with('"') {
+            val[1..val.len() - 1]
+                .replace("\\n", "\n")
+                .replace("\\\"", "\"")
+                .replace("\\\\", "\\")
+        } else {
+            // Strip any trailing comment from an unquoted value
+            match val.find(" #") {
+                Some(idx) => val[..idx].trim_end().to_string(),
+                None => val.to_string(),
+            }
+        };
+
+        ret.push((key.to_string(), val));
+    }
+
+    return Ok(ret);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(k: &str, v: &str) -> (String, String) {
+        return (k.to_string(), v.to_string());
+    }
+
+    #[test]
+    fn test_parse_env_var() {
+        assert_eq!(Ok(pair("A", "b=c")), parse_env_var("A=b=c"));
+        assert_eq!(Ok(pair("_X1", "")), parse_env_var("_X1="));
+        assert!(parse_env_var("NOVALUE").is_err());
+        assert!(parse_env_var("1A=b").is_err());
+        assert!(parse_env_var("=b").is_err());
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let contents = "# A comment\n\
+            \n\
+            FOO=bar\n\
+            export BAZ = qux # trailing\n\
+            SINGLE='a # b'\n\
+            DOUBLE=\"line\\nnext\"\n";
+
+        assert_eq!(
+            Ok(vec![
+                pair("FOO", "bar"),
+                pair("BAZ", "qux"),
+                pair("SINGLE", "a # b"),
+                pair("DOUBLE", "line\nnext"),
+            ]),
+            parse_env_file(contents)
+        );
+
+        assert!(parse_env_file("FOO=bar\nnot a var\n").is_err());
+        assert!(parse_env_file("BAD-NAME=1\n").is_err());
+    }
+
+    #[test]
+    fn test_effective() {
+        let opts = EnvOptions {
+            clear: true,
+            path: Some("/bin".to_string()),
+            vars: vec![pair("A", "1"), pair("API_TOKEN", "abc")],
+            ..Default::default()
+        };
+
+        let vars = opts.build().unwrap();
+        let eff = opts.effective(&vars);
+
+        assert_eq!(3, eff.len());
+        assert_eq!("1", eff["A"]);
+        assert_eq!("/bin", eff["PATH"]);
+        assert_eq!("<redacted>", eff["API_TOKEN"]);
+    }
+}
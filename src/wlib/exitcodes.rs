@@ -0,0 +1,72 @@
+/// A set of exit codes, stored as inclusive ranges
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExitCodes {
+    ranges: Vec<(i32, i32)>,
+}
+
+impl ExitCodes {
+    pub fn contains(&self, code: i32) -> bool {
+        return self
+            .ranges
+            .iter()
+            .any(|(lo, hi)| *lo <= code && code <= *hi);
+    }
+
+    /// Parse a comma separated list of codes and inclusive ranges, e.g.
+    /// "0,1,20-25"
+    pub fn parse(codes: &str) -> Result<Self, String> {
+        let mut ranges = vec![];
+        for part in codes.split(',') {
+            let part = part.trim();
+            let range = match part.split_once('-') {
+                Some((lo, hi)) => (lo.trim().parse::<i32>(), hi.trim().parse::<i32>()),
+                None => (part.parse::<i32>(), part.parse::<i32>()),
+            };
+
+            match range {
+                (Ok(lo), Ok(hi)) if lo <= hi => ranges.push((lo, hi)),
+                _ => return Err(format!("Invalid exit code or range: {}", part)),
+            }
+        }
+
+        return Ok(Self { ranges });
+    }
+}
+
+impl Default for ExitCodes {
+    fn default() -> Self {
+        return Self {
+            ranges: vec![(0, 0)],
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let codes = ExitCodes::parse("0, 1,20-25").unwrap();
+        assert!(codes.contains(0));
+        assert!(codes.contains(1));
+        assert!(codes.contains(20));
+        assert!(codes.contains(24));
+        assert!(codes.contains(25));
+        assert!(!codes.contains(2));
+        assert!(!codes.contains(26));
+        assert!(!codes.contains(-1));
+
+        assert!(ExitCodes::parse("").is_err());
+        assert!(ExitCodes::parse("1,").is_err());
+        assert!(ExitCodes::parse("5-2").is_err());
+        assert!(ExitCodes::parse("a").is_err());
+    }
+
+    #[test]
+    fn test_default() {
+        let codes = ExitCodes::default();
+        assert!(codes.contains(0));
+        assert!(!codes.contains(1));
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+
+fn set_cloexec(fd: RawFd, on: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let new_flags = match on {
+        true => flags | libc::FD_CLOEXEC,
+        false => flags & !libc::FD_CLOEXEC,
+    };
+    if new_flags != flags && unsafe { libc::fcntl(fd, libc::F_SETFD, new_flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    return Ok(());
+}
+
+/// Check that the descriptor is open
+pub fn is_open(fd: RawFd) -> bool {
+    return unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0;
+}
+
+/// Mark every open descriptor above stderr, other than those in `keep`, as
+/// close-on-exec so the command only gets the descriptors it's meant to.
+/// This covers anything leaked to us by whatever started cwrap.
+pub fn close_on_exec(keep: &[RawFd]) -> io::Result<()> {
+    // Collect these first as reading the directory opens a descriptor too
+    let fds: Vec<RawFd> = fs::read_dir("/proc/self/fd")?
+        .flatten()
+        .filter_map(|e| e.file_name().to_string_lossy().parse().ok())
+        .collect();
+
+    for fd in fds {
+        if fd <= 2 || keep.contains(&fd) {
+            continue;
+        }
+
+        match set_cloexec(fd, true) {
+            Ok(_) => (),
+            // This was the descriptor for the directory listing
+            Err(e) if e.raw_os_error() == Some(libc::EBADF) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    return Ok(());
+}
+
+/// Clear close-on-exec on the descriptor so it stays open across an exec
+pub fn inherit(fd: RawFd) -> io::Result<()> {
+    return set_cloexec(fd, false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    fn cloexec(fd: RawFd) -> bool {
+        return unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC != 0;
+    }
+
+    #[test]
+    fn test_close_on_exec() {
+        let f = fs::File::open("/dev/null").unwrap();
+        let fd = f.as_raw_fd();
+        inherit(fd).unwrap();
+        assert!(!cloexec(fd));
+
+        close_on_exec(&[fd]).unwrap();
+        assert!(!cloexec(fd));
+
+        close_on_exec(&[]).unwrap();
+        assert!(cloexec(fd));
+        assert!(is_open(fd));
+    }
+}
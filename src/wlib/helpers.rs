@@ -3,6 +3,7 @@ use chrono::{TimeZone, Utc};
 use hostname;
 use std::process::id;
 use std::str::FromStr;
+use std::time::Duration;
 use syslog::{Facility, Formatter3164, Logger, LoggerBackend, Severity};
 
 #[macro_export]
@@ -43,16 +44,11 @@ pub struct SyslogHelper {
 
 impl SyslogHelper {
     pub fn new(severity: &str, facility: &str) -> Self {
-        let loc_hostname = match hostname::get() {
-            Ok(name) => Some(name.into_string().unwrap()),
-            Err(_) => None,
-        };
-
         let sev = syslog_severity_from_str(severity).ok().unwrap();
 
         let formatter = Formatter3164 {
             facility: Facility::from_str(facility).unwrap(),
-            hostname: loc_hostname,
+            hostname: get_hostname(),
             process: "cwrap".to_string(),
             pid: id(),
         };
@@ -92,7 +88,6 @@ pub fn format_ts(ts: f64) -> String {
 
 /// Check for a "/" in the cmd, and if it's there, just get the
 /// binary name
-#[allow(dead_code)]
 pub fn basename(path: &str) -> String {
     return match path.find("/") {
         Some(_) => {
@@ -122,6 +117,135 @@ pub fn sanitize_path(path: &str, sep: char) -> String {
     return ret;
 }
 
+/// Parse a size in bytes, e.g. "512", "100K" or "2G".  The K, M, G and T
+/// suffixes are binary and case insensitive.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (num, mult) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 1 << 10),
+        Some('M') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') => (&size[..size.len() - 1], 1 << 30),
+        Some('T') => (&size[..size.len() - 1], 1 << 40),
+        _ => (size, 1),
+    };
+
+    return match num.parse::<u64>() {
+        Ok(n) => n
+            .checked_mul(mult)
+            .ok_or(format!("Size is too large: {}", size)),
+        Err(_) => Err(format!("Invalid size: {}", size)),
+    };
+}
+
+/// Parse an octal file mode creation mask, e.g. "022", up to "777"
+pub fn parse_umask(mask: &str) -> Result<u32, String> {
+    return match u32::from_str_radix(mask.trim(), 8) {
+        Ok(m) if m <= 0o777 => Ok(m),
+        _ => Err(format!("Invalid umask, expected an octal mode: {}", mask)),
+    };
+}
+
+/// Parse a duration such as "30s", "5m", "1.5h" or "1d".  A plain number is
+/// taken as seconds.
+pub fn parse_duration(dur: &str) -> Result<Duration, String> {
+    let dur = dur.trim();
+    let (num, mult) = match dur.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('s') => (&dur[..dur.len() - 1], 1.0),
+        Some('m') => (&dur[..dur.len() - 1], 60.0),
+        Some('h') => (&dur[..dur.len() - 1], 3600.0),
+        Some('d') => (&dur[..dur.len() - 1], 86400.0),
+        _ => (dur, 1.0),
+    };
+
+    let secs = match num.parse::<f64>() {
+        Ok(n) if n >= 0.0 && n.is_finite() => n * mult,
+        _ => return Err(format!("Invalid duration: {}", dur)),
+    };
+
+    return Duration::try_from_secs_f64(secs).map_err(|_| format!("Duration is too long: {}", dur));
+}
+
+/// Parse a retry backoff factor, which is a number no less than 1
+pub fn parse_backoff(factor: &str) -> Result<f64, String> {
+    return match factor.trim().parse::<f64>() {
+        Ok(f) if f.is_finite() && f >= 1.0 => Ok(f),
+        _ => Err(format!(
+            "Invalid backoff, expected a number of at least 1: {}",
+            factor
+        )),
+    };
+}
+
+/// The longest a retry will wait, no matter how far it has backed off
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(86400);
+
+/// Multiply the retry delay by the backoff factor, capped at
+/// MAX_RETRY_DELAY
+pub fn backoff(delay: Duration, factor: f64) -> Duration {
+    return Duration::try_from_secs_f64(delay.as_secs_f64() * factor)
+        .unwrap_or(MAX_RETRY_DELAY)
+        .min(MAX_RETRY_DELAY);
+}
+
+/// Get the system hostname, if it can be found
+pub fn get_hostname() -> Option<String> {
+    return match hostname::get() {
+        Ok(name) => Some(name.to_string_lossy().to_string()),
+        Err(_) => None,
+    };
+}
+
+/// Derive a fuzz delay, between 0 and `max` seconds, from the host and job
+/// names.  This is the same on every run of a job on a host, but varies
+/// across hosts, so a fleet is spread out while each host keeps a regular
+/// schedule.
+pub fn stable_fuzz(host: &str, job: &str, max: u64) -> u64 {
+    let digest = md5::compute(format!("{}\0{}", host, job).as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+
+    return u64::from_be_bytes(bytes) % (max + 1);
+}
+
+/// Remove ANSI escape sequences (colors, cursor movement, terminal titles,
+/// etc.) from the text
+pub fn strip_ansi(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            ret.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            // CSI: ESC [ <params> <final byte in @-~>
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: ESC ] ... terminated by BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    } else if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Everything else is a 2 character sequence
+            _ => (),
+        }
+    }
+
+    return ret;
+}
+
 #[test]
 fn test_sanitize_path() {
     assert_eq!("cd", sanitize_path("cd", '-'));
@@ -148,3 +272,94 @@ fn test_format_ts() {
     assert_eq!("Thu, 1 Jan 1970 00:00:00 +0000", format_ts(0.0));
     assert_eq!("Wed, 31 Dec 1969 23:59:59 +0000", format_ts(-1.0));
 }
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(Ok(100), parse_size("100"));
+    assert_eq!(Ok(2048), parse_size("2K"));
+    assert_eq!(Ok(512 * 1024 * 1024), parse_size("512m"));
+    assert_eq!(Ok(1 << 30), parse_size("1G"));
+    assert!(parse_size("").is_err());
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("12X").is_err());
+    assert!(parse_size("99999999999T").is_err());
+}
+
+#[test]
+fn test_parse_umask() {
+    assert_eq!(Ok(0o022), parse_umask("022"));
+    assert_eq!(Ok(0o077), parse_umask("77"));
+    assert_eq!(Ok(0), parse_umask("0"));
+    assert!(parse_umask("800").is_err());
+    assert!(parse_umask("1777").is_err());
+    assert!(parse_umask("abc").is_err());
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(Ok(Duration::from_secs(30)), parse_duration("30"));
+    assert_eq!(Ok(Duration::from_secs(30)), parse_duration("30s"));
+    assert_eq!(Ok(Duration::from_secs(300)), parse_duration("5m"));
+    assert_eq!(Ok(Duration::from_secs(5400)), parse_duration("1.5h"));
+    assert_eq!(Ok(Duration::from_secs(86400)), parse_duration("1D"));
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("s").is_err());
+    assert!(parse_duration("-5s").is_err());
+    assert!(parse_duration("5w").is_err());
+    assert!(parse_duration("1e25").is_err());
+    assert!(parse_duration("1e300").is_err());
+    assert!(parse_duration("1e30s").is_err());
+}
+
+#[test]
+fn test_parse_backoff() {
+    assert_eq!(Ok(1.0), parse_backoff("1"));
+    assert_eq!(Ok(2.5), parse_backoff(" 2.5 "));
+    assert!(parse_backoff("0.5").is_err());
+    assert!(parse_backoff("-1").is_err());
+    assert!(parse_backoff("NaN").is_err());
+    assert!(parse_backoff("inf").is_err());
+    assert!(parse_backoff("x").is_err());
+}
+
+#[test]
+fn test_backoff() {
+    let mut delay = Duration::from_secs(30);
+    let mut seq = vec![];
+    for _ in 0..4 {
+        seq.push(delay.as_secs());
+        delay = backoff(delay, 2.0);
+    }
+    assert_eq!(vec![30, 60, 120, 240], seq);
+
+    assert_eq!(
+        Duration::from_secs(30),
+        backoff(Duration::from_secs(30), 1.0)
+    );
+    assert_eq!(MAX_RETRY_DELAY, backoff(Duration::from_secs(30), 1e300));
+    assert_eq!(MAX_RETRY_DELAY, backoff(MAX_RETRY_DELAY, 1.5));
+}
+
+#[test]
+fn test_stable_fuzz() {
+    let delay = stable_fuzz("web1", "backup.abc", 300);
+    assert!(delay <= 300);
+    assert_eq!(delay, stable_fuzz("web1", "backup.abc", 300));
+    assert_eq!(0, stable_fuzz("web1", "backup.abc", 0));
+
+    // Different hosts get spread across the window
+    let delays: Vec<u64> = (0..10)
+        .map(|i| stable_fuzz(&format!("web{}", i), "backup.abc", 300))
+        .collect();
+    assert!(delays.iter().any(|d| *d != delays[0]));
+}
+
+#[test]
+fn test_strip_ansi() {
+    assert_eq!("plain", strip_ansi("plain"));
+    assert_eq!("red text", strip_ansi("\x1b[31mred\x1b[0m text"));
+    assert_eq!("50%", strip_ansi("\x1b[2K\x1b[1G50%"));
+    assert_eq!("title", strip_ansi("\x1b]0;my title\x07title"));
+    assert_eq!("st", strip_ansi("\x1b]0;x\x1b\\st"));
+    assert_eq!("ab", strip_ansi("a\x1b=b"));
+}
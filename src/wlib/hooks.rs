@@ -0,0 +1,282 @@
+use super::cmdstate::{prepare_command, wait_helper, CmdRun, CmdState};
+use super::runopts::RunOptions;
+use serde_json::json;
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The points in a run at which a hook can be run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookEvent {
+    Start,
+    Success,
+    Failure,
+    /// A success after one or more failures
+    Recovery,
+}
+
+impl HookEvent {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            HookEvent::Start => "start",
+            HookEvent::Success => "success",
+            HookEvent::Failure => "failure",
+            HookEvent::Recovery => "recovery",
+        };
+    }
+}
+
+/// The commands to run at each point in a run, which are run under the
+/// shell with the details of the run in the environment and on stdin
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pub on_start: Option<String>,
+    pub on_success: Option<String>,
+    pub on_failure: Option<String>,
+    pub on_recovery: Option<String>,
+    pub timeout: Duration,
+}
+
+impl Hooks {
+    fn hook(&self, event: HookEvent) -> &Option<String> {
+        return match event {
+            HookEvent::Start => &self.on_start,
+            HookEvent::Success => &self.on_success,
+            HookEvent::Failure => &self.on_failure,
+            HookEvent::Recovery => &self.on_recovery,
+        };
+    }
+
+    /// Run the hook for the event, if there is one, in the same environment,
+    /// directory and as the same user as the command.  The run is None for
+    /// the start hook.  This returns an error describing how the hook failed.
+    pub fn run(
+        &self,
+        event: HookEvent,
+        state: &CmdState,
+        run: Option<&CmdRun>,
+        opts: &RunOptions,
+    ) -> Result<(), String> {
+        let hook = match self.hook(event) {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+
+        let doc = json!({
+            "event": event.name(),
+            "command": state.cli_to_string(),
+            "num_fails": state.num_fails,
+            "run": run,
+        });
+
+        let mut command = Command::new(state.shell_path());
+        prepare_command(&mut command, opts)?;
+        let mut child = match command
+            .args(["-c", hook])
+            .envs(env_vars(event, state, run))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()
+        {
+            Ok(c) => c,
+            Err(e) => return Err(format!("failed to start: {}", e)),
+        };
+
+        // These are done in the background so a hook that doesn't read its
+        // stdin, or writes a lot to stderr, can't block us
+        let mut stdin = child.stdin.take().unwrap();
+        thread::spawn(move || {
+            stdin.write_all(doc.to_string().as_bytes()).ok();
+        });
+        let mut stderr = child.stderr.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = String::new();
+            stderr.read_to_string(&mut buf).ok();
+            tx.send(buf).ok();
+        });
+
+        // The hook can't run past the '--deadline' any more than the command
+        let timeout_at = Instant::now().checked_add(self.timeout);
+        let limit = match (timeout_at, opts.deadline) {
+            (Some(t), Some(d)) => Some(t.min(d)),
+            (t, d) => t.or(d),
+        };
+        let status = match wait_helper(&mut child, limit) {
+            Ok(Some(status)) => status,
+            Ok(None) if limit != timeout_at => {
+                return Err("killed at the deadline".to_string());
+            }
+            Ok(None) => {
+                return Err(format!(
+                    "timed out after {:.0} secs",
+                    self.timeout.as_secs_f64()
+                ));
+            }
+            Err(e) => return Err(format!("failed waiting on the hook: {}", e)),
+        };
+        // Anything the hook left running in the background can hold its
+        // stdin and stderr open, so the writer is never waited on and the
+        // reader only until the timeout
+        let stderr = match limit {
+            Some(l) => rx
+                .recv_timeout(l.saturating_duration_since(Instant::now()))
+                .unwrap_or_default(),
+            None => rx.recv().unwrap_or_default(),
+        };
+
+        if !status.success() && stderr.trim().is_empty() {
+            return Err(status.to_string());
+        } else if !status.success() {
+            return Err(format!("{}: {}", status, stderr.trim()));
+        }
+
+        return Ok(());
+    }
+}
+
+/// The variables describing the run that are set in the hook's environment
+fn env_vars(event: HookEvent, state: &CmdState, run: Option<&CmdRun>) -> Vec<(String, String)> {
+    let mut ret = vec![
+        ("CWRAP_EVENT".to_string(), event.name().to_string()),
+        ("CWRAP_COMMAND".to_string(), state.cli_to_string()),
+        ("CWRAP_NUM_FAILS".to_string(), state.num_fails.to_string()),
+    ];
+
+    if let Some(run) = run {
+        ret.push(("CWRAP_EXIT_CODE".to_string(), run.exit_code.to_string()));
+        ret.push((
+            "CWRAP_START_TIME".to_string(),
+            format!("{:.3}", run.start_time),
+        ));
+        ret.push(("CWRAP_RUN_TIME".to_string(), format!("{:.3}", run.run_time)));
+        if let Some(sig) = &run.signal_name {
+            ret.push(("CWRAP_SIGNAL".to_string(), sig.clone()));
+        }
+        if let Some(reason) = run.reason() {
+            ret.push(("CWRAP_REASON".to_string(), reason));
+        }
+    }
+
+    return ret;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Instant;
+
+    fn hooks(cmd: &str) -> Hooks {
+        return Hooks {
+            on_failure: Some(cmd.to_string()),
+            timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn test_env_vars() {
+        let mut state = CmdState::new(&vec!["false".to_string()], false);
+        state.num_fails = 3;
+        let mut run = CmdRun::for_test(1, None);
+        run.rust_err = Some("Command reached timeout of 5 secs".to_string());
+
+        let vars = env_vars(HookEvent::Failure, &state, Some(&run));
+        assert!(vars.contains(&("CWRAP_EVENT".to_string(), "failure".to_string())));
+        assert!(vars.contains(&("CWRAP_NUM_FAILS".to_string(), "3".to_string())));
+        assert!(vars.contains(&("CWRAP_EXIT_CODE".to_string(), "1".to_string())));
+        assert!(vars.contains(&(
+            "CWRAP_REASON".to_string(),
+            "Command reached timeout of 5 secs".to_string()
+        )));
+
+        let vars = env_vars(HookEvent::Start, &state, None);
+        assert_eq!(3, vars.len());
+    }
+
+    #[test]
+    fn test_run() {
+        let state = CmdState::new(&vec!["false".to_string()], false);
+        let mut run = CmdRun::for_test(0, None);
+        run.rust_err = Some("boom".to_string());
+        let opts = RunOptions::for_test(&[]);
+
+        // The details are on stdin as JSON
+        let check = r#"grep -q '"event":"failure"' && [ "$CWRAP_REASON" = boom ]"#;
+        assert_eq!(
+            Ok(()),
+            hooks(check).run(HookEvent::Failure, &state, Some(&run), &opts)
+        );
+
+        // There is no hook for this event
+        assert_eq!(
+            Ok(()),
+            hooks("exit 1").run(HookEvent::Success, &state, Some(&run), &opts)
+        );
+
+        assert_eq!(
+            Err("exit status: 3".to_string()),
+            hooks("exit 3").run(HookEvent::Failure, &state, Some(&run), &opts)
+        );
+        assert_eq!(
+            Err("exit status: 2: oops".to_string()),
+            hooks("echo oops >&2; exit 2").run(HookEvent::Failure, &state, Some(&run), &opts)
+        );
+
+        let mut slow = hooks("sleep 5");
+        slow.timeout = Duration::from_millis(100);
+        assert!(slow
+            .run(HookEvent::Failure, &state, Some(&run), &opts)
+            .is_err());
+
+        // Something left running in the background with our stderr doesn't
+        // keep us waiting past the timeout
+        let mut bg = hooks("sleep 5 & exit 0");
+        bg.timeout = Duration::from_millis(500);
+        let started = Instant::now();
+        assert_eq!(
+            Ok(()),
+            bg.run(HookEvent::Failure, &state, Some(&run), &opts)
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // The deadline cuts the hook short too
+        let opts = RunOptions::for_test(&["--deadline", "1"]);
+        let started = Instant::now();
+        assert_eq!(
+            Err("killed at the deadline".to_string()),
+            hooks("sleep 5").run(HookEvent::Failure, &state, Some(&run), &opts)
+        );
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_run_like_command() {
+        let dir = std::env::temp_dir().join(format!("cwrap-hook-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("cwrap-test-hook");
+        fs::write(
+            &script,
+            "#!/bin/sh\n[ \"$FOO\" = bar ] && [ \"$(pwd)\" = / ]\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        // The hook is found through '--path' and sees the command's
+        // environment and directory
+        let path = format!("{}:/usr/bin:/bin", dir.display());
+        let opts = RunOptions::for_test(&["--path", &path, "--env", "FOO=bar", "--chdir", "/"]);
+        let state = CmdState::new(&vec!["false".to_string()], false);
+        let run = CmdRun::for_test(1, None);
+        let res = hooks("cwrap-test-hook").run(HookEvent::Failure, &state, Some(&run), &opts);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(Ok(()), res);
+    }
+}
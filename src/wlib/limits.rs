@@ -0,0 +1,189 @@
+use super::cmdstate::CmdRun;
+use std::io;
+
+/// Resource limits to apply to the child via setrlimit() before it's exec'd
+#[derive(Clone, Default)]
+pub struct ResourceLimits {
+    /// The max address space in bytes
+    pub mem: Option<u64>,
+    /// The max CPU time in seconds
+    pub cpu: Option<u64>,
+    /// The max number of open file descriptors
+    pub nofile: Option<u64>,
+    /// The max size of a file the child can write, in bytes
+    pub fsize: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        return self.mem.is_none()
+            && self.cpu.is_none()
+            && self.nofile.is_none()
+            && self.fsize.is_none();
+    }
+
+    /// Set the limits on the current process with setrlimit()
+    pub fn apply(&self) -> io::Result<()> {
+        if let Some(v) = self.mem {
+            Self::check(unsafe { libc::setrlimit(libc::RLIMIT_AS, &Self::rlim(v, v)) })?;
+        }
+
+        if let Some(v) = self.cpu {
+            // Leave a second between the soft and hard limit so the child
+            // gets a SIGXCPU, which we can identify, before the SIGKILL
+            Self::check(unsafe {
+                libc::setrlimit(libc::RLIMIT_CPU, &Self::rlim(v, v.saturating_add(1)))
+            })?;
+        }
+
+        if let Some(v) = self.nofile {
+            Self::check(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &Self::rlim(v, v)) })?;
+        }
+
+        if let Some(v) = self.fsize {
+            Self::check(unsafe { libc::setrlimit(libc::RLIMIT_FSIZE, &Self::rlim(v, v)) })?;
+        }
+
+        return Ok(());
+    }
+
+    /// Describe the limits for a report, returning None if there are none
+    pub fn desc(&self) -> Option<String> {
+        let mut ret = vec![];
+        if let Some(v) = self.mem {
+            ret.push(format!("memory {} bytes", v));
+        }
+        if let Some(v) = self.cpu {
+            ret.push(format!("CPU {} secs", v));
+        }
+        if let Some(v) = self.nofile {
+            ret.push(format!("{} open files", v));
+        }
+        if let Some(v) = self.fsize {
+            ret.push(format!("file size {} bytes", v));
+        }
+
+        if ret.is_empty() {
+            return None;
+        }
+
+        return Some(ret.join(", "));
+    }
+
+    /// Determine whether a failed run was killed for going over one of the
+    /// limits, returning a description of the violation if so.  Only the
+    /// CPU and file size limits are enforced with a signal, so memory and
+    /// open file limit failures can't be told apart from any other error.
+    pub fn violation(&self, run: &CmdRun) -> Option<String> {
+        let sig = run.signal?;
+
+        if let Some(cpu) = self.cpu {
+            let cpu_time = match &run.rusage {
+                Some(u) => u.user_time + u.sys_time,
+                None => 0.0,
+            };
+
+            // The hard limit is a SIGKILL, which only counts if the time
+            // was actually used
+            if sig == libc::SIGXCPU || (sig == libc::SIGKILL && cpu_time >= cpu as f64) {
+                return Some(format!("CPU time limit of {} secs exceeded", cpu));
+            }
+        }
+
+        if let Some(fsize) = self.fsize {
+            if sig == libc::SIGXFSZ {
+                return Some(format!("File size limit of {} bytes exceeded", fsize));
+            }
+        }
+
+        return None;
+    }
+
+    fn rlim(cur: u64, max: u64) -> libc::rlimit {
+        return libc::rlimit {
+            rlim_cur: cur as libc::rlim_t,
+            rlim_max: max as libc::rlim_t,
+        };
+    }
+
+    fn check(ret: libc::c_int) -> io::Result<()> {
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wlib::rusage::ResourceUsage;
+
+    #[test]
+    fn test_desc() {
+        assert_eq!(None, ResourceLimits::default().desc());
+
+        let limits = ResourceLimits {
+            mem: Some(1024),
+            nofile: Some(64),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some("memory 1024 bytes, 64 open files".to_string()),
+            limits.desc()
+        );
+    }
+
+    #[test]
+    fn test_violation() {
+        let limits = ResourceLimits {
+            mem: Some(1024),
+            cpu: Some(10),
+            nofile: Some(64),
+            fsize: Some(2048),
+        };
+
+        assert_eq!(None, limits.violation(&CmdRun::for_test(0, None)));
+        assert_eq!(None, limits.violation(&CmdRun::for_test(1, None)));
+        assert_eq!(
+            Some("CPU time limit of 10 secs exceeded".to_string()),
+            limits.violation(&CmdRun::for_test(-1, Some(libc::SIGXCPU)))
+        );
+        assert_eq!(
+            Some("File size limit of 2048 bytes exceeded".to_string()),
+            limits.violation(&CmdRun::for_test(-1, Some(libc::SIGXFSZ)))
+        );
+
+        // Neither an exit code that looks like a signal nor what the command
+        // said on stderr proves anything
+        assert_eq!(
+            None,
+            limits.violation(&CmdRun::for_test(128 + libc::SIGXFSZ, None))
+        );
+        assert_eq!(
+            None,
+            limits.violation(&CmdRun::for_test(1, None).with_output("", "out of memory"))
+        );
+
+        // A SIGKILL only counts against the CPU limit if the time was used
+        let mut run = CmdRun::for_test(-1, Some(libc::SIGKILL));
+        assert_eq!(None, limits.violation(&run));
+        run.rusage = Some(ResourceUsage {
+            user_time: 9.0,
+            sys_time: 1.5,
+            max_rss_kb: 0,
+            in_blocks: 0,
+            out_blocks: 0,
+            vol_ctx_switches: 0,
+            invol_ctx_switches: 0,
+        });
+        assert!(limits.violation(&run).is_some());
+
+        // Nothing is reported for limits that weren't set
+        assert_eq!(
+            None,
+            ResourceLimits::default().violation(&CmdRun::for_test(-1, Some(libc::SIGXCPU)))
+        );
+    }
+}
@@ -1,27 +1,49 @@
 extern crate random_number;
 
 use super::cmdstate;
+use super::conditions::Conditions;
 use super::errors::lockfile;
-use super::helpers::{format_ts, SyslogHelper};
+use super::exitcodes::ExitCodes;
+use super::helpers::{backoff, format_ts, get_hostname, stable_fuzz, SyslogHelper};
+use super::hooks::{HookEvent, Hooks};
+use super::outrules::{OutputFilter, OutputRules};
+use super::period::Period;
+use super::runopts::RunOptions;
+use super::signals;
 use super::smtp::{send_email, SMTPOptions};
 use super::statefile::StateFile;
 use crate::sleep_ms;
 use crate::Args;
+use chrono::Local;
 use log::{debug, error};
 use random_number::random;
 use serde_json;
 use std::path::PathBuf;
 use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct RunManager {
     cmd_state: cmdstate::CmdState,
     syslog: Option<SyslogHelper>,
     statefile: StateFile,
     fuzz: usize,
+    stable_fuzz: bool,
+    deadline: Option<Duration>,
     num_retries: usize,
     retry_secs: usize,
     ignore_retry_fails: bool,
-    timeout: usize,
+    run_options: RunOptions,
+    conditions: Conditions,
+    hooks: Hooks,
+    once_per: Option<Period>,
+    ok_exit_codes: ExitCodes,
+    output_rules: OutputRules,
+    output_filter: OutputFilter,
+    retry_on_failure: usize,
+    retry_delay: Duration,
+    retry_backoff: f64,
+    warn_after: Option<Duration>,
     quiet: bool,
     num_fails: usize,
     backoff: bool,
@@ -30,11 +52,21 @@ pub struct RunManager {
 }
 
 impl RunManager {
-    pub fn new(args: &Args) -> Self {
-        let mut statefile = StateFile::from_strs(
-            &StateFile::gen_name(&args.cmd, args.bash_string),
-            &args.state_dir,
-        );
+    /// Set up the manager for the args.  `started` is when cwrap started,
+    /// which the '--deadline' counts from.
+    pub fn new(args: &Args, started: Instant) -> Self {
+        // The steps of a multi-step job are always shell strings
+        let multi_step = !args.step.is_empty();
+        let (cmd, bash_string) = match multi_step {
+            true => (&args.step, true),
+            false => (&args.cmd, args.bash_string),
+        };
+
+        let name = match multi_step {
+            true => StateFile::gen_steps_name(cmd),
+            false => StateFile::gen_name(cmd, bash_string),
+        };
+        let mut statefile = StateFile::from_strs(&name, &args.state_dir);
 
         if let Some(f) = &args.lock_file {
             statefile.overwrite_lockfile(PathBuf::from(f));
@@ -42,9 +74,9 @@ impl RunManager {
 
         // First, we try and load the CmdState from disk and create it
         // otherwise
-        let cmd_state = match cmdstate::CmdState::load(&statefile) {
+        let mut cmd_state = match cmdstate::CmdState::load(&statefile) {
             Ok(Some(v)) => v,
-            Ok(None) => cmdstate::CmdState::new(&args.cmd, args.bash_string),
+            Ok(None) => cmdstate::CmdState::new(cmd, bash_string),
             Err(e) => {
                 panic!(
                     "Error loading command state from statefile {}: {}",
@@ -54,11 +86,30 @@ impl RunManager {
             }
         };
 
+        // The shell options aren't part of the state file name, so they
+        // always come from the cli rather than the saved state
+        if bash_string {
+            cmd_state.shell = Some(args.shell.clone().unwrap_or("bash".to_string()));
+        } else {
+            cmd_state.shell = None;
+        }
+        cmd_state.strict_shell = args.strict_shell;
+        cmd_state.multi_step = multi_step;
+
         let mut syslog = None;
         if args.syslog {
             syslog = Some(SyslogHelper::new(&args.syslog_pri, &args.syslog_fac));
         }
 
+        let mut output_filter = OutputFilter {
+            patterns: args.ignore_output.clone(),
+        };
+        if let Some(path) = &args.ignore_output_file {
+            if let Err(e) = output_filter.load_file(path) {
+                panic!("Error loading the output ignore patterns: {}", e);
+            }
+        }
+
         let smtp_options = SMTPOptions::from_args(args);
 
         return Self {
@@ -66,10 +117,36 @@ impl RunManager {
             syslog: syslog,
             statefile: statefile,
             fuzz: args.fuzz,
+            stable_fuzz: args.stable_fuzz,
+            deadline: args.deadline,
             num_retries: args.num_retries,
             retry_secs: args.retry_secs,
             ignore_retry_fails: args.ignore_retry_fails,
-            timeout: args.timeout,
+            run_options: RunOptions::from_args(args, started),
+            conditions: Conditions {
+                only_if: args.only_if.clone(),
+                only_if_file: args.only_if_file.clone(),
+                skip_if_file: args.skip_if_file.clone(),
+            },
+            once_per: args.once_per,
+            hooks: Hooks {
+                on_start: args.on_start.clone(),
+                on_success: args.on_success.clone(),
+                on_failure: args.on_failure.clone(),
+                on_recovery: args.on_recovery.clone(),
+                timeout: args.hook_timeout,
+            },
+            ok_exit_codes: args.ok_exit_codes.clone(),
+            output_rules: OutputRules {
+                fail_on_stderr: args.fail_on_stderr,
+                stdout_matches: args.fail_if_stdout_matches.clone(),
+                output_missing: args.fail_if_output_missing.clone(),
+            },
+            output_filter: output_filter,
+            retry_on_failure: args.retry_on_failure,
+            retry_delay: args.retry_delay,
+            retry_backoff: args.retry_backoff,
+            warn_after: args.warn_after,
             quiet: args.quiet,
             num_fails: args.num_fails,
             backoff: args.backoff,
@@ -79,34 +156,181 @@ impl RunManager {
     }
 
     pub fn run_instance(&mut self, lock: bool) {
+        // There's no point in a fuzz if the run is going to be skipped, but
+        // the skip is recorded once we have the lock, like any other
+        let period_skip = self.succeeded_this_period();
+
         let fuzz = self.fuzz as u64;
-        if self.fuzz > 0 {
-            // Sleep for a random bit here
-            let sl_time: u64 = random!(..=fuzz);
+        let mut fuzz_secs = None;
+        let fuzz_start = Instant::now();
+        if self.fuzz > 0 && period_skip.is_none() {
+            // Sleep for a random, or stable per host, bit here
+            let mut sl_time: u64 = match self.stable_fuzz {
+                true => stable_fuzz(
+                    &get_hostname().unwrap_or_default(),
+                    &self.statefile.name,
+                    fuzz,
+                ),
+                false => random!(..=fuzz),
+            };
+            // Don't sleep away the time left to run the command
+            if let Some(left) = self.time_left() {
+                sl_time = sl_time.min(left.as_secs());
+            }
             debug!("Sleeping (fuzz) for {} secs", sl_time);
             sleep_ms!(sl_time * 1000);
+            fuzz_secs = Some(sl_time);
         }
 
+        let lock_start = Instant::now();
         if lock {
             if let Err(e) = self.lock() {
                 if !self.ignore_retry_fails {
-                    error!(
-                        "Could not get lock to run instance in {} retries: {}",
-                        self.num_retries, e,
-                    );
+                    if self.time_left() == Some(Duration::ZERO) {
+                        error!(
+                            "Could not get lock to run instance before the deadline: {}",
+                            e
+                        );
+                    } else {
+                        error!(
+                            "Could not get lock to run instance in {} retries: {}",
+                            self.num_retries, e,
+                        );
+                    }
                     exit(1);
                 }
             }
         }
+        let run_start = Instant::now();
+
+        // From here, a signal is forwarded to whatever is running for the
+        // job, the conditions and hooks included, and we stop once that's
+        // exited, so nothing is left running
+        signals::set_running();
+
+        if let Some(reason) = period_skip {
+            self.skip(reason);
+            return;
+        }
+
+        // The conditions are checked while holding the lock so the skip is
+        // recorded in the state like any other run
+        let checked = self
+            .conditions
+            .check(&self.cmd_state.shell_path(), &self.run_options);
+        if signals::received().is_some() {
+            self.interrupt("the conditions were checked");
+            return;
+        }
+        match checked {
+            Ok(None) => (),
+            Ok(Some(reason)) => {
+                self.skip(reason);
+                return;
+            }
+            Err(e) => {
+                // The job can't be run as configured, which is a failure
+                // like the command itself not starting
+                let run = cmdstate::CmdRun::rust_err(e);
+                self.cmd_state
+                    .record_history(&run, cmdstate::RunStatus::Failure);
+                self.handle_failure(run);
+                if let Err(e) = self.cmd_state.save(&self.statefile) {
+                    error!("Serialize failure: {}", e);
+                }
+                return;
+            }
+        }
+
+        self.run_hook(HookEvent::Start, None);
+        if signals::received().is_some() {
+            self.interrupt("the start hook was run");
+            return;
+        }
+        let mut run = self.run_cmd();
+
+        // Retry a failed command immediately, if requested, holding on to
+        // the failed attempts for the report
+        let mut attempts = vec![];
+        let mut delay = self.retry_delay;
+        while !run.is_success(&self.ok_exit_codes)
+            && run.interrupted.is_none()
+            && attempts.len() < self.retry_on_failure
+        {
+            debug!(
+                "Attempt {} failed, retrying in {:.1} secs",
+                attempts.len() + 1,
+                delay.as_secs_f64()
+            );
+            if self.time_left().map(|left| left <= delay) == Some(true) {
+                debug!("Not retrying as the deadline would be reached first");
+                break;
+            }
+            if !Self::sleep_unless_signaled(delay) {
+                // We were told to stop while waiting to retry, which is no
+                // more a failure than a signal during the run
+                run.interrupted = signals::received().map(cmdstate::CmdRun::sig_to_name);
+                break;
+            }
+            delay = backoff(delay, self.retry_backoff);
 
-        let run = cmdstate::CmdRun::run(&self.cmd_state, self.cmd_state.bash_string, self.timeout);
-        if run.exit_code != 0 || run.rust_err.is_some() {
+            attempts.push(run);
+            run = self.run_cmd();
+        }
+        run.fuzz_secs = fuzz_secs;
+        if let Some(deadline) = self.deadline {
+            run.phases = Some(cmdstate::PhaseTimes {
+                deadline: deadline.as_secs_f64(),
+                fuzz: (lock_start - fuzz_start).as_secs_f64(),
+                lock_wait: (run_start - lock_start).as_secs_f64(),
+                run: run_start.elapsed().as_secs_f64(),
+            });
+        }
+        run.attempt = attempts.len() + 1;
+        run.prev_attempts = attempts;
+
+        if let Some(warn_after) = self.warn_after {
+            run.slow = run.run_time > warn_after.as_secs_f64();
+        }
+
+        if let Some(sig) = &run.interrupted {
+            // We were told to stop, so this doesn't count as either a
+            // success or a failure
+            debug!("The command was interrupted by {}", sig);
+            self.log(&format!(
+                "CWRAP INTERRUPTED `{}`: received {}, which was forwarded to the command",
+                self.cmd_state.cli_to_string(),
+                sig,
+            ));
+            self.cmd_state
+                .record_history(&run, cmdstate::RunStatus::Interrupted);
+        } else if !run.is_success(&self.ok_exit_codes) {
             // We have a failure of some sort here
+            self.cmd_state
+                .record_history(&run, cmdstate::RunStatus::Failure);
             self.handle_failure(run);
         } else {
-            if !self.quiet {
+            self.cmd_state
+                .record_history(&run, cmdstate::RunStatus::Success);
+            // The environment is only included in failure reports
+            run.env = None;
+            // If everything the command output was ignored, there's nothing
+            // worth reporting
+            let all_ignored =
+                run.ignored_lines > 0 && run.stdout.is_empty() && run.stderr.is_empty();
+            if run.slow {
+                // This is a warning rather than a success, so it's reported
+                // even with --quiet
+                self.print_slow_report(&run);
+            } else if !self.quiet && !all_ignored {
                 self.print_success_report(&run);
             }
+
+            self.cmd_state.last_success = Some(run.start_time + run.run_time);
+            self.run_hook(HookEvent::Success, Some(&run));
+            if self.cmd_state.num_fails > 0 {
+                self.run_hook(HookEvent::Recovery, Some(&run));
+            }
             self.cmd_state.reset();
         }
 
@@ -115,9 +339,140 @@ impl RunManager {
         }
     }
 
+    /// The time left until the '--deadline', if there is one
+    fn time_left(&self) -> Option<Duration> {
+        return self.run_options.time_left();
+    }
+
+    /// Check whether the command has already succeeded in the current
+    /// '--once-per' period, returning the reason to skip the run if it has
+    fn succeeded_this_period(&self) -> Option<String> {
+        let (period, last) = match (&self.once_per, self.cmd_state.last_success) {
+            (Some(p), Some(l)) => (p, l),
+            _ => return None,
+        };
+
+        if period.contains(last, Local::now()) {
+            return Some(format!(
+                "The command already succeeded this period, at {}",
+                format_ts(last)
+            ));
+        }
+
+        return None;
+    }
+
+    /// Record a run that was skipped as its preconditions weren't met.  This
+    /// leaves the failure count alone, so it neither resets nor adds to a
+    /// run of failures.
+    fn skip(&mut self, reason: String) {
+        debug!("Skipping the run: {}", reason);
+        self.log(&format!(
+            "CWRAP SKIPPED `{}`: {}",
+            self.cmd_state.cli_to_string(),
+            reason,
+        ));
+        self.cmd_state.record_skip(reason);
+        if let Err(e) = self.cmd_state.save(&self.statefile) {
+            error!("Serialize failure: {}", e);
+        }
+    }
+
+    /// Record a run that was interrupted before the command was started,
+    /// while `stage`.  Like any other interruption, this is neither a success
+    /// nor a failure.
+    fn interrupt(&mut self, stage: &str) {
+        let sig = signals::received()
+            .map(cmdstate::CmdRun::sig_to_name)
+            .unwrap_or_default();
+        debug!("Interrupted by {} while {}", sig, stage);
+        self.log(&format!(
+            "CWRAP INTERRUPTED `{}`: received {} while {}, so the command wasn't run",
+            self.cmd_state.cli_to_string(),
+            sig,
+            stage,
+        ));
+
+        self.cmd_state
+            .record_interrupt(format!("Interrupted by {} while {}", sig, stage));
+        if let Err(e) = self.cmd_state.save(&self.statefile) {
+            error!("Serialize failure: {}", e);
+        }
+    }
+
+    /// Run the hook for the event, if any.  A failed hook is only logged, so
+    /// it never changes the result of the run.
+    fn run_hook(&mut self, event: HookEvent, run: Option<&cmdstate::CmdRun>) {
+        if let Err(e) = self
+            .hooks
+            .run(event, &self.cmd_state, run, &self.run_options)
+        {
+            error!("The {} hook failed: {}", event.name(), e);
+            self.log(&format!(
+                "CWRAP HOOK FAILURE for `{}`: the {} hook failed: {}",
+                self.cmd_state.cli_to_string(),
+                event.name(),
+                e,
+            ));
+        }
+    }
+
+    /// Sleep for the duration, returning early if a termination signal is
+    /// received.  This returns false if the sleep was interrupted.
+    fn sleep_unless_signaled(dur: Duration) -> bool {
+        let end = Instant::now() + dur;
+        while signals::received().is_none() {
+            let now = Instant::now();
+            if now >= end {
+                return true;
+            }
+            sleep_ms!((end - now).min(Duration::from_millis(100)).as_millis() as u64);
+        }
+
+        return false;
+    }
+
+    /// Run the command, applying the output filter and rules to the result
+    fn run_cmd(&self) -> cmdstate::CmdRun {
+        let mut run;
+        if self.cmd_state.multi_step {
+            run = self.run_steps();
+        } else {
+            run = cmdstate::CmdRun::run(&self.cmd_state, &self.run_options);
+            self.output_filter.apply(&mut run);
+        }
+        if run.fail_reason.is_none() && run.rust_err.is_none() {
+            run.fail_reason = self.output_rules.check(&run);
+        }
+
+        return run;
+    }
+
+    /// Run the steps of a multi-step job in order, stopping at the first
+    /// one that fails.  The output rules apply to the output of the job as a
+    /// whole, so they are left to the caller.
+    fn run_steps(&self) -> cmdstate::CmdRun {
+        let mut steps = vec![];
+        let job_started = Instant::now();
+        for idx in 0..self.cmd_state.cmd.len() {
+            let mut step =
+                cmdstate::CmdRun::run_step(&self.cmd_state, idx, &self.run_options, job_started);
+            self.output_filter.apply(&mut step);
+            let success = step.is_success(&self.ok_exit_codes);
+            steps.push(step);
+            if !success {
+                debug!("Step {} failed, skipping the rest", idx + 1);
+                break;
+            }
+        }
+
+        return cmdstate::CmdRun::from_steps(steps);
+    }
+
     /// Generate and print a report if necessary, per the cli opts
     fn handle_failure(&mut self, run: cmdstate::CmdRun) {
         self.cmd_state.num_fails += 1;
+        self.run_hook(HookEvent::Failure, Some(&run));
 
         if self.syslog.is_some() {
             // Need to serialize the command run and write that
@@ -154,7 +509,7 @@ impl RunManager {
                 row: {}\n\nFAILURES:\n",
             self.num_fails,
             self.cmd_state.num_fails,
-            &self.cmd_state.cli_to_string(),
+            self.report_cmd(),
         ));
 
         // First, we print out the previous runs
@@ -163,9 +518,40 @@ impl RunManager {
         }
 
         self.add_run_report(&mut output, run);
+        self.emit_report(&output, None);
+
+        // And finally, reset the command state
+        self.cmd_state.reset_runs();
+    }
 
+    /// Print a warning report for a run that succeeded, but took longer than
+    /// the '--warn-after' threshold
+    fn print_slow_report(&mut self, run: &cmdstate::CmdRun) {
+        let warn_after = self.warn_after.unwrap_or_default().as_secs_f64();
+        self.log(&format!(
+            "CWRAP SLOW RUN for `{}`: took {:.2} secs, over the warning threshold of {:.2} secs",
+            self.cmd_state.cli_to_string(),
+            run.run_time,
+            warn_after,
+        ));
+
+        let mut output = String::new();
+        output.push_str(&format!(
+            "WARNING: The following command succeeded, but took {:.2} secs, \
+                which is over the warning threshold of {:.2} secs: {}\n\n",
+            run.run_time,
+            warn_after,
+            self.report_cmd(),
+        ));
+        self.add_run_report(&mut output, run);
+        self.emit_report(&output, Some("cwrap slow run warning"));
+    }
+
+    /// Send the report by email and/or print it, per the cli opts.  The
+    /// subject overrides the configured email subject.
+    fn emit_report(&self, output: &str, subject: Option<&str>) {
         if self.smtp_options.send_email {
-            if let Err(e) = send_email(&output, &self.smtp_options) {
+            if let Err(e) = send_email(output, subject, &self.smtp_options) {
                 print!(
                     "*** Failed to send the email using internal transport ***\nError: {}\n",
                     e
@@ -177,9 +563,6 @@ impl RunManager {
         if !self.smtp_options.send_email || self.smtp_options.also_normal_output {
             print!("{}", output);
         }
-
-        // And finally, reset the command state
-        self.cmd_state.reset_runs();
     }
 
     fn print_success_report(&self, run: &cmdstate::CmdRun) {
@@ -190,23 +573,95 @@ impl RunManager {
         print!("{}", output);
     }
 
+    /// The command for a report, with the steps of a multi-step job starting
+    /// on a line of their own
+    fn report_cmd(&self) -> String {
+        if self.cmd_state.multi_step {
+            return format!("\n{}", self.cmd_state.cli_to_string());
+        }
+
+        return self.cmd_state.cli_to_string();
+    }
+
     /// This will add to the building of a string for the failure report for a
     /// single run
     fn add_run_report(&self, rep: &mut String, fail: &cmdstate::CmdRun) {
         let f_div = "=====\n";
         let out_div = "-----\n";
         rep.push_str(f_div);
-        rep.push_str(&format!("Command: {}\n", &self.cmd_state.cli_to_string()));
+        rep.push_str(&format!("Command: {}\n", self.report_cmd()));
+        if self.cmd_state.bash_string {
+            rep.push_str(&format!("Shell: {}\n", self.cmd_state.shell_desc()));
+        }
         rep.push_str(&format!("Start Time: {}\n", format_ts(fail.start_time)));
+        if let Some(p) = &fail.phases {
+            rep.push_str(&format!("Deadline (seconds): {}\n", p.desc()));
+        }
+        if let Some(secs) = fail.fuzz_secs {
+            let kind = if self.stable_fuzz {
+                "stable per host"
+            } else {
+                "random"
+            };
+            rep.push_str(&format!("Fuzz Delay (seconds): {} ({})\n", secs, kind));
+        }
         rep.push_str(&format!("Run Time (seconds): {:.2}\n", fail.run_time));
         rep.push_str("Exit Code: ");
         if let Some(e) = &fail.rust_err {
             rep.push_str(&format!("Internal Error: {}\n", e));
         } else {
-            rep.push_str(&format!("{}\n", fail.exit_code));
+            rep.push_str(&fail.exit_desc());
+            if fail.exit_code != 0 && fail.is_success(&self.ok_exit_codes) {
+                rep.push_str(" (allowed)");
+            }
+            rep.push_str("\n");
+        }
+
+        if fail.pty {
+            rep.push_str("Terminal: pty (stdout and stderr are combined)\n");
+        }
+
+        if let Some(limits) = &fail.limits {
+            rep.push_str(&format!("Limits: {}\n", limits));
+        }
+
+        if let Some(usage) = &fail.rusage {
+            usage.add_report(rep);
+        }
+
+        if fail.slow {
+            rep.push_str(&format!(
+                "Slow Run: over the warning threshold of {:.2} secs\n",
+                self.warn_after.unwrap_or_default().as_secs_f64(),
+            ));
+        }
+
+        if let Some(sig) = &fail.interrupted {
+            rep.push_str(&format!(
+                "Interrupted: cwrap received {}, which was forwarded to the command\n",
+                sig
+            ));
         }
 
-        if !fail.stdout.is_empty() {
+        let success = fail.is_success(&self.ok_exit_codes);
+        if fail.attempt > 1 && success {
+            rep.push_str(&format!("Succeeded on attempt {}\n", fail.attempt));
+        } else if fail.attempt > 1 {
+            rep.push_str(&format!("Failed all {} attempts\n", fail.attempt));
+        }
+
+        if fail.ignored_lines > 0 {
+            rep.push_str(&format!("Ignored Output Lines: {}\n", fail.ignored_lines));
+        }
+
+        if let Some(reason) = &fail.fail_reason {
+            rep.push_str(&format!("Failure Reason: {}\n", reason));
+        }
+
+        if !fail.steps.is_empty() {
+            // The output is broken down by step instead
+            self.add_steps_report(rep, fail);
+        } else if !fail.stdout.is_empty() {
             rep.push_str("\n");
             rep.push_str(&format!("STDOUT:\n{}", out_div));
             rep.push_str(&fail.stdout);
@@ -214,16 +669,94 @@ impl RunManager {
             rep.push_str(out_div);
         }
 
-        if !fail.stderr.is_empty() {
+        if fail.steps.is_empty() && !fail.stderr.is_empty() {
             rep.push_str("\n");
             rep.push_str(&format!("STDERR:\n{}", out_div));
             rep.push_str(&fail.stderr);
             rep.push_str("\n");
             rep.push_str(out_div);
         }
+
+        if !success && !fail.prev_attempts.is_empty() {
+            rep.push_str("\nPREVIOUS ATTEMPTS:\n");
+            for (i, prev) in fail.prev_attempts.iter().enumerate() {
+                rep.push_str(&format!("Attempt {}:\n", i + 1));
+                self.add_run_report(rep, prev);
+            }
+        }
+
+        if !fail.last_output.is_empty() {
+            rep.push_str("\n");
+            rep.push_str(&format!("LAST OUTPUT BEFORE IDLE TIMEOUT:\n{}", out_div));
+            for line in &fail.last_output {
+                rep.push_str(line);
+                rep.push_str("\n");
+            }
+            rep.push_str(out_div);
+        }
+
+        if !fail.orphans.is_empty() {
+            rep.push_str("\n");
+            rep.push_str(&format!(
+                "PROCESSES LEFT RUNNING BY THE COMMAND:\n{}",
+                out_div
+            ));
+            for orphan in &fail.orphans {
+                rep.push_str(orphan);
+                rep.push_str("\n");
+            }
+            rep.push_str(out_div);
+        }
+
+        if let Some(env) = &fail.env {
+            rep.push_str("\n");
+            rep.push_str(&format!("ENVIRONMENT:\n{}", out_div));
+            for (k, v) in env {
+                rep.push_str(&format!("{}={}\n", k, v));
+            }
+            rep.push_str(out_div);
+        }
         rep.push_str(f_div);
     }
 
+    /// Add a section for each step of a multi-step job to the report,
+    /// including any steps that weren't run
+    fn add_steps_report(&self, rep: &mut String, run: &cmdstate::CmdRun) {
+        let out_div = "-----\n";
+        let total = self.cmd_state.cmd.len();
+        rep.push_str("\nSTEPS:\n");
+        for (idx, cmd) in self.cmd_state.cmd.iter().enumerate() {
+            rep.push_str(&format!("\nStep {} of {}: {}\n", idx + 1, total, cmd));
+            let step = match run.steps.get(idx) {
+                Some(s) => s,
+                None => {
+                    rep.push_str("Not run\n");
+                    continue;
+                }
+            };
+
+            rep.push_str(&format!("Run Time (seconds): {:.2}\n", step.run_time));
+            rep.push_str("Exit Code: ");
+            match &step.rust_err {
+                Some(e) => rep.push_str(&format!("Internal Error: {}\n", e)),
+                None => rep.push_str(&format!("{}\n", step.exit_desc())),
+            }
+
+            if let Some(reason) = &step.fail_reason {
+                rep.push_str(&format!("Failure Reason: {}\n", reason));
+            }
+
+            for (name, text) in [("STDOUT", &step.stdout), ("STDERR", &step.stderr)] {
+                if !text.is_empty() {
+                    rep.push_str(&format!("{}:\n{}", name, out_div));
+                    rep.push_str(text);
+                    rep.push_str("\n");
+                    rep.push_str(out_div);
+                }
+            }
+        }
+    }
+
     fn backoff_match(&self) -> bool {
         let mut count = self.num_fails;
         while count <= self.cmd_state.num_fails {
@@ -250,9 +783,17 @@ impl RunManager {
         while tries > try_count {
             debug!("Attempting to acquire lock to run");
             ret = self.statefile.lock();
-            if ret.is_err() && tries > 0 {
+            if ret.is_err() && self.time_left() == Some(Duration::ZERO) {
+                debug!("The deadline was reached waiting for the lock");
+                break;
+            } else if ret.is_err() && tries > 0 {
                 try_count += 1;
-                sleep_ms!(ret_secs * 1000);
+                // Wake up in time to give up at the deadline
+                let wait = match self.time_left() {
+                    Some(left) => left.min(Duration::from_secs(ret_secs)),
+                    None => Duration::from_secs(ret_secs),
+                };
+                thread::sleep(wait);
             } else {
                 break;
             }
@@ -1,6 +1,21 @@
 pub mod cmdstate;
+pub mod conditions;
+pub mod environ;
 pub mod errors;
+pub mod exitcodes;
+pub mod fds;
 pub mod helpers;
+pub mod hooks;
+pub mod limits;
 pub mod manager;
+pub mod outrules;
+pub mod period;
+pub mod priority;
+pub mod privs;
+pub mod pty;
+pub mod runopts;
+pub mod rusage;
+pub mod signals;
 pub mod smtp;
 pub mod statefile;
+pub mod subreaper;
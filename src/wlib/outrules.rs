@@ -0,0 +1,187 @@
+use super::cmdstate::CmdRun;
+use regex::Regex;
+use std::fs;
+use std::path::Path;
+
+/// Patterns for known, harmless output that should be dropped from the
+/// captured output before it's checked or reported
+#[derive(Clone, Default)]
+pub struct OutputFilter {
+    pub patterns: Vec<Regex>,
+}
+
+impl OutputFilter {
+    pub fn is_empty(&self) -> bool {
+        return self.patterns.is_empty();
+    }
+
+    /// Load patterns from a file, one regex per line.  Blank lines and lines
+    /// starting with "#" are skipped.
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match Regex::new(line) {
+                Ok(re) => self.patterns.push(re),
+                Err(e) => return Err(format!("Invalid pattern in {}: {}", path.display(), e)),
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Remove the lines matching any of the patterns from the run's stdout
+    /// and stderr, recording how many were dropped
+    pub fn apply(&self, run: &mut CmdRun) {
+        if self.is_empty() {
+            return;
+        }
+
+        let (stdout, out_count) = self.filter(&run.stdout);
+        let (stderr, err_count) = self.filter(&run.stderr);
+        run.stdout = stdout;
+        run.stderr = stderr;
+        run.ignored_lines += out_count + err_count;
+    }
+
+    /// Filter the text, returning what's left and the number of lines removed
+    fn filter(&self, text: &str) -> (String, usize) {
+        let mut ret = String::new();
+        let mut count = 0;
+        for line in text.lines() {
+            if self.patterns.iter().any(|re| re.is_match(line)) {
+                count += 1;
+            } else {
+                ret.push_str(line);
+                ret.push('\n');
+            }
+        }
+
+        if !text.ends_with('\n') && ret.ends_with('\n') {
+            ret.pop();
+        }
+
+        return (ret, count);
+    }
+}
+
+/// Rules that mark a run as failed based on its output, for commands that
+/// don't exit with a failure code when something goes wrong
+#[derive(Clone, Default)]
+pub struct OutputRules {
+    /// Fail if there is any output on stderr
+    pub fail_on_stderr: bool,
+    /// Fail if any line of stdout matches one of these
+    pub stdout_matches: Vec<Regex>,
+    /// Fail if no line of output (stdout or stderr) matches one of these
+    pub output_missing: Vec<Regex>,
+}
+
+impl OutputRules {
+    /// Check the output of the run against the rules, returning the reason
+    /// the run failed if one of them was tripped
+    pub fn check(&self, run: &CmdRun) -> Option<String> {
+        if self.fail_on_stderr {
+            if let Some(line) = run.stderr.lines().find(|l| !l.trim().is_empty()) {
+                return Some(format!("Output on stderr: {}", line));
+            }
+        }
+
+        for re in &self.stdout_matches {
+            if let Some(line) = run.stdout.lines().find(|l| re.is_match(l)) {
+                return Some(format!("Stdout matched /{}/: {}", re, line));
+            }
+        }
+
+        for re in &self.output_missing {
+            let found = run
+                .stdout
+                .lines()
+                .chain(run.stderr.lines())
+                .any(|l| re.is_match(l));
+            if !found {
+                return Some(format!("No output matched /{}/", re));
+            }
+        }
+
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter = OutputFilter {
+            patterns: vec![
+                Regex::new("DeprecationWarning").unwrap(),
+                Regex::new("^perl: warning: Setting locale").unwrap(),
+            ],
+        };
+
+        let mut run = CmdRun::for_test(0, None).with_output(
+            "start\nfoo DeprecationWarning bar\nend\n",
+            "perl: warning: Setting locale failed.\n",
+        );
+        filter.apply(&mut run);
+
+        assert_eq!("start\nend\n", run.stdout);
+        assert_eq!("", run.stderr);
+        assert_eq!(2, run.ignored_lines);
+
+        let (text, count) = filter.filter("no newline");
+        assert_eq!("no newline", text);
+        assert_eq!(0, count);
+    }
+
+    #[test]
+    fn test_check() {
+        let mut rules = OutputRules::default();
+        assert_eq!(
+            None,
+            rules.check(&CmdRun::for_test(0, None).with_output("ERROR: x", "warning"))
+        );
+
+        rules.fail_on_stderr = true;
+        assert_eq!(
+            None,
+            rules.check(&CmdRun::for_test(0, None).with_output("", "\n  \n"))
+        );
+        assert_eq!(
+            Some("Output on stderr: warning".to_string()),
+            rules.check(&CmdRun::for_test(0, None).with_output("", "\nwarning\nmore"))
+        );
+
+        rules.fail_on_stderr = false;
+        rules.stdout_matches = vec![Regex::new("^ERROR").unwrap()];
+        assert_eq!(
+            None,
+            rules.check(&CmdRun::for_test(0, None).with_output("an ERROR\n", "ERROR"))
+        );
+        assert_eq!(
+            Some("Stdout matched /^ERROR/: ERROR: disk full".to_string()),
+            rules.check(&CmdRun::for_test(0, None).with_output("ok\nERROR: disk full\n", ""))
+        );
+
+        rules.stdout_matches = vec![];
+        rules.output_missing = vec![Regex::new("backup complete").unwrap()];
+        assert_eq!(
+            None,
+            rules.check(&CmdRun::for_test(0, None).with_output("", "backup complete\n"))
+        );
+        assert_eq!(
+            Some("No output matched /backup complete/".to_string()),
+            rules.check(&CmdRun::for_test(0, None).with_output("started\n", ""))
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use super::helpers::parse_duration;
+use chrono::{DateTime, Duration as ChronoDuration, TimeZone, Timelike};
+use std::time::Duration;
+
+/// A period in which a command only needs to succeed once
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    /// The calendar day, in local time
+    Day,
+    /// The clock hour, in local time
+    Hour,
+    /// The given time before now
+    Rolling(Duration),
+}
+
+impl Period {
+    /// The time at which the period containing `now` started
+    pub fn start<Tz: TimeZone>(&self, now: DateTime<Tz>) -> DateTime<Tz> {
+        let hour = now
+            .with_minute(0)
+            .and_then(|t| t.with_second(0))
+            .and_then(|t| t.with_nanosecond(0));
+
+        return match self {
+            // If midnight or the top of the hour doesn't exist, due to a DST
+            // change, fall back to a rolling period
+            Period::Day => hour
+                .and_then(|t| t.with_hour(0))
+                .unwrap_or(now - ChronoDuration::days(1)),
+            Period::Hour => hour.unwrap_or(now - ChronoDuration::hours(1)),
+            Period::Rolling(d) => {
+                now - ChronoDuration::from_std(*d).unwrap_or(ChronoDuration::zero())
+            }
+        };
+    }
+
+    /// Whether the timestamp falls in the period containing `now`
+    pub fn contains<Tz: TimeZone>(&self, ts: f64, now: DateTime<Tz>) -> bool {
+        let start = self.start(now.clone());
+        let start = start.timestamp() as f64 + start.timestamp_subsec_nanos() as f64 / 1e9;
+        let now = now.timestamp() as f64 + now.timestamp_subsec_nanos() as f64 / 1e9;
+
+        return ts >= start && ts <= now;
+    }
+}
+
+/// Parse a period, which is either "day" or "hour", aligned to local time,
+/// or a duration for a rolling period ending now, e.g. "12h"
+pub fn parse_period(val: &str) -> Result<Period, String> {
+    return match &val.trim().to_lowercase()[..] {
+        "day" | "daily" => Ok(Period::Day),
+        "hour" | "hourly" => Ok(Period::Hour),
+        other => match parse_duration(other) {
+            Ok(d) if !d.is_zero() => Ok(Period::Rolling(d)),
+            _ => Err(format!(
+                "Invalid period, expected day, hour or a duration: {}",
+                val
+            )),
+        },
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_parse_period() {
+        assert_eq!(Ok(Period::Day), parse_period("day"));
+        assert_eq!(Ok(Period::Hour), parse_period("Hourly"));
+        assert_eq!(
+            Ok(Period::Rolling(Duration::from_secs(43200))),
+            parse_period("12h")
+        );
+        assert!(parse_period("0s").is_err());
+        assert!(parse_period("week").is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 10, 14, 30, 0).unwrap();
+        let ts = |h, m| {
+            Utc.with_ymd_and_hms(2024, 3, 10, h, m, 0)
+                .unwrap()
+                .timestamp() as f64
+        };
+
+        assert!(Period::Day.contains(ts(0, 0), now));
+        assert!(!Period::Day.contains(ts(0, 0) - 1.0, now));
+        assert!(Period::Hour.contains(ts(14, 0), now));
+        assert!(!Period::Hour.contains(ts(13, 59), now));
+
+        let rolling = Period::Rolling(Duration::from_secs(3600));
+        assert!(rolling.contains(ts(13, 30), now));
+        assert!(!rolling.contains(ts(13, 29), now));
+        // Nothing in the future counts
+        assert!(!Period::Day.contains(ts(15, 0), now));
+    }
+}
@@ -0,0 +1,98 @@
+use std::io;
+
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// An I/O scheduling class and level, as used by ioprio_set()
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IoPriority {
+    pub class: u32,
+    pub level: u32,
+}
+
+/// The CPU and I/O scheduling priority for the child
+#[derive(Clone, Default)]
+pub struct Priority {
+    pub nice: Option<i32>,
+    pub ionice: Option<IoPriority>,
+}
+
+impl Priority {
+    pub fn is_empty(&self) -> bool {
+        return self.nice.is_none() && self.ionice.is_none();
+    }
+
+    /// Set the nice value and I/O scheduling priority of the current process
+    pub fn apply(&self) -> io::Result<()> {
+        if let Some(nice) = self.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        if let Some(io) = self.ionice {
+            let prio = (io.class << IOPRIO_CLASS_SHIFT) | io.level;
+            let ret = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// Parse an I/O priority in the form of <class>[:<level>].  The class can be
+/// a name (realtime, best-effort, idle) or the number used by ionice(1), and
+/// the level is 0-7, e.g. "idle" or "best-effort:7".
+pub fn parse_ionice(val: &str) -> Result<IoPriority, String> {
+    let (class_s, level_s) = match val.split_once(':') {
+        Some((c, l)) => (c, Some(l)),
+        None => (val, None),
+    };
+
+    let class = match &class_s.to_lowercase()[..] {
+        "1" | "realtime" | "rt" => 1,
+        "2" | "best-effort" | "be" => 2,
+        "3" | "idle" => 3,
+        _ => return Err(format!("Invalid I/O scheduling class: {}", class_s)),
+    };
+
+    let level = match level_s {
+        Some(l) => match l.parse::<u32>() {
+            Ok(n) if n <= 7 => n,
+            _ => return Err(format!("Invalid I/O priority level (0-7): {}", l)),
+        },
+        // This is the default level for best-effort
+        None => 4,
+    };
+
+    // The level doesn't apply to the idle class
+    if class == 3 {
+        return Ok(IoPriority { class, level: 0 });
+    }
+
+    return Ok(IoPriority { class, level });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ionice() {
+        assert_eq!(
+            Ok(IoPriority { class: 2, level: 7 }),
+            parse_ionice("best-effort:7")
+        );
+        assert_eq!(Ok(IoPriority { class: 2, level: 4 }), parse_ionice("2"));
+        assert_eq!(Ok(IoPriority { class: 1, level: 0 }), parse_ionice("rt:0"));
+        assert_eq!(
+            Ok(IoPriority { class: 3, level: 0 }),
+            parse_ionice("idle:5")
+        );
+        assert!(parse_ionice("fast").is_err());
+        assert!(parse_ionice("be:8").is_err());
+        assert!(parse_ionice("be:").is_err());
+    }
+}
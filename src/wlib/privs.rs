@@ -0,0 +1,225 @@
+use std::ffi::CString;
+use std::io;
+use std::path::PathBuf;
+use users::os::unix::UserExt;
+use users::{get_effective_uid, get_group_by_gid, get_group_by_name};
+use users::{get_user_by_name, get_user_by_uid};
+
+// These take 16 bit ids on some 32 bit targets, so there are separate
+// versions for 32 bit ids
+#[cfg(any(target_arch = "arm", target_arch = "x86"))]
+const SYS_SETGROUPS: libc::c_long = libc::SYS_setgroups32;
+#[cfg(not(any(target_arch = "arm", target_arch = "x86")))]
+const SYS_SETGROUPS: libc::c_long = libc::SYS_setgroups;
+
+/// The user and group to switch to in the child before the command is exec'd
+#[derive(Clone)]
+pub struct Credentials {
+    pub uid: Option<libc::uid_t>,
+    pub gid: libc::gid_t,
+    /// All the groups the command runs with, the same as initgroups() sets.
+    /// These are looked up before the fork as initgroups() isn't safe to call
+    /// in the child.
+    pub groups: Vec<libc::gid_t>,
+    /// The user name, used for the child's USER and LOGNAME
+    pub name: Option<CString>,
+    pub home: Option<PathBuf>,
+}
+
+impl Credentials {
+    /// Resolve the user and/or group (names or numeric ids) to the ids to
+    /// switch to.  This returns None if neither was specified.
+    pub fn resolve(user: &Option<String>, group: &Option<String>) -> Result<Option<Self>, String> {
+        if user.is_none() && group.is_none() {
+            return Ok(None);
+        }
+
+        if get_effective_uid() != 0 {
+            return Err("cwrap must be run as root to change the user or group".to_string());
+        }
+
+        let mut ret = Self {
+            uid: None,
+            gid: 0,
+            groups: vec![],
+            name: None,
+            home: None,
+        };
+
+        if let Some(u) = user {
+            let found = match u.parse::<libc::uid_t>() {
+                Ok(uid) => get_user_by_uid(uid),
+                Err(_) => get_user_by_name(u),
+            };
+            let found = match found {
+                Some(f) => f,
+                None => return Err(format!("Unknown user: {}", u)),
+            };
+
+            ret.uid = Some(found.uid());
+            ret.gid = found.primary_group_id();
+            ret.home = Some(found.home_dir().to_path_buf());
+            ret.name = match CString::new(found.name().to_string_lossy().as_bytes()) {
+                Ok(n) => Some(n),
+                Err(e) => return Err(format!("Invalid user name {}: {}", u, e)),
+            };
+        }
+
+        if let Some(g) = group {
+            let found = match g.parse::<libc::gid_t>() {
+                Ok(gid) => get_group_by_gid(gid),
+                Err(_) => get_group_by_name(g),
+            };
+            ret.gid = match found {
+                Some(f) => f.gid(),
+                None => return Err(format!("Unknown group: {}", g)),
+            };
+        }
+
+        ret.groups = match &ret.name {
+            Some(name) => Self::group_list(name, ret.gid)?,
+            None => vec![ret.gid],
+        };
+
+        return Ok(Some(ret));
+    }
+
+    /// Look up the groups of the user, including `gid`
+    fn group_list(name: &CString, gid: libc::gid_t) -> Result<Vec<libc::gid_t>, String> {
+        let mut groups: Vec<libc::gid_t> = vec![0; 64];
+        loop {
+            let mut count = groups.len() as libc::c_int;
+            let ret =
+                unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+            // The count is set to the number of groups needed if there
+            // wasn't enough room
+            if ret >= 0 {
+                groups.truncate(count as usize);
+                return Ok(groups);
+            } else if count as usize <= groups.len() {
+                return Err(format!(
+                    "Failed to look up the groups for {}",
+                    name.to_string_lossy()
+                ));
+            }
+            groups.resize(count as usize, 0);
+        }
+    }
+
+    /// Run `f` with the filesystem ids and groups of this thread switched to
+    /// the user, so that it can only open what the command could.  The raw
+    /// syscalls are used to switch the groups as the libc wrapper switches
+    /// every thread in the process.
+    pub fn as_user<T, F: FnOnce() -> T>(&self, f: F) -> io::Result<T> {
+        let uid = match self.uid {
+            Some(u) => u,
+            // The command runs as root, so it can open anything we can
+            None => return Ok(f()),
+        };
+
+        let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        let mut saved: Vec<libc::gid_t> = vec![0; count.max(0) as usize];
+        if unsafe { libc::getgroups(count, saved.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Self::check(
+            unsafe { libc::syscall(SYS_SETGROUPS, self.groups.len(), self.groups.as_ptr()) }
+                as libc::c_int,
+        )?;
+        // Switching the filesystem uid away from root also drops the
+        // capabilities that let root open anything
+        let old_gid = unsafe { libc::setfsgid(self.gid) };
+        let old_uid = unsafe { libc::setfsuid(uid) };
+
+        let ret = f();
+
+        unsafe {
+            libc::setfsuid(old_uid as libc::uid_t);
+            libc::setfsgid(old_gid as libc::gid_t);
+        }
+        Self::check(
+            unsafe { libc::syscall(SYS_SETGROUPS, saved.len(), saved.as_ptr()) } as libc::c_int,
+        )?;
+
+        return Ok(ret);
+    }
+
+    /// The variables to set in the child's environment for the user
+    pub fn env_vars(&self) -> Vec<(String, String)> {
+        let mut ret = vec![];
+        if let Some(name) = &self.name {
+            let name = name.to_string_lossy().to_string();
+            ret.push(("USER".to_string(), name.clone()));
+            ret.push(("LOGNAME".to_string(), name));
+        }
+
+        if let Some(home) = &self.home {
+            ret.push(("HOME".to_string(), home.to_string_lossy().to_string()));
+        }
+
+        return ret;
+    }
+
+    /// Switch the current process to the group, the user's supplementary
+    /// groups and then the user.  Nothing that requires root works after
+    /// this.
+    pub fn apply(&self) -> io::Result<()> {
+        // The group has to be set first, while we are still root
+        Self::check(unsafe { libc::setgid(self.gid) })?;
+
+        Self::check(unsafe { libc::setgroups(self.groups.len(), self.groups.as_ptr()) })?;
+
+        if let Some(uid) = self.uid {
+            Self::check(unsafe { libc::setuid(uid) })?;
+        }
+
+        return Ok(());
+    }
+
+    fn check(ret: libc::c_int) -> io::Result<()> {
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_as_user() {
+        // Switching users needs root
+        if get_effective_uid() != 0 {
+            return;
+        }
+
+        let path = std::env::temp_dir().join(format!("cwrap-privs-test-{}", std::process::id()));
+        fs::write(&path, "secret").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let creds = Credentials::resolve(&Some("nobody".to_string()), &None)
+            .unwrap()
+            .unwrap();
+        let res = creds.as_user(|| File::open(&path)).unwrap();
+        assert_eq!(
+            io::ErrorKind::PermissionDenied,
+            res.map(|_| ()).unwrap_err().kind()
+        );
+
+        // We're back to root afterwards
+        assert!(File::open(&path).is_ok());
+        fs::remove_file(&path).unwrap();
+
+        // Only the group is switched, and the command runs as root
+        let creds = Credentials::resolve(&None, &Some("nogroup".to_string()))
+            .unwrap()
+            .unwrap();
+        assert!(creds.as_user(|| File::open("/etc/shadow")).unwrap().is_ok());
+    }
+}
@@ -0,0 +1,146 @@
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::FromRawFd;
+use std::process::Stdio;
+
+/// The size to give the terminal, as some tools change their output based on
+/// it and a pty starts out as 0x0
+const PTY_ROWS: u16 = 24;
+const PTY_COLS: u16 = 80;
+
+/// Check whether our stdin is a terminal
+pub fn stdin_is_tty() -> bool {
+    return unsafe { libc::isatty(0) } == 1;
+}
+
+/// A pseudo-terminal pair for running the command under
+pub struct Pty {
+    pub master: File,
+    slave: File,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Self> {
+        let master_fd =
+            unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+        if master_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { File::from_raw_fd(master_fd) };
+
+        if unsafe { libc::grantpt(master_fd) } != 0 || unsafe { libc::unlockpt(master_fd) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut name = [0 as libc::c_char; 128];
+        if unsafe { libc::ptsname_r(master_fd, name.as_mut_ptr(), name.len()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let slave_fd = unsafe {
+            libc::open(
+                CStr::from_ptr(name.as_ptr()).as_ptr(),
+                libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC,
+            )
+        };
+        if slave_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let slave = unsafe { File::from_raw_fd(slave_fd) };
+
+        let size = libc::winsize {
+            ws_row: PTY_ROWS,
+            ws_col: PTY_COLS,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        unsafe { libc::ioctl(slave_fd, libc::TIOCSWINSZ, &size) };
+
+        return Ok(Self { master, slave });
+    }
+
+    /// Split the pty into the master, for reading the output, and handles to
+    /// the terminal for the child's stdout and stderr.  Once the child is
+    /// spawned, those are the only handles to the terminal, so reading from
+    /// the master ends when the child (and anything it started) exits.
+    pub fn into_parts(self) -> io::Result<(File, Stdio, Stdio)> {
+        let stdout = Stdio::from(self.slave.try_clone()?);
+        let stderr = Stdio::from(self.slave);
+
+        return Ok((self.master, stdout, stderr));
+    }
+
+    /// Start a new session and make the terminal on stdout the controlling
+    /// terminal of the current process
+    pub fn make_controlling() -> io::Result<()> {
+        if unsafe { libc::setsid() } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if unsafe { libc::ioctl(1, libc::TIOCSCTTY, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wlib::cmdstate::{CmdRun, CmdState};
+    use crate::wlib::runopts::RunOptions;
+    use crate::Args;
+    use clap::Parser;
+    use std::env;
+    use std::io::{Read, Write};
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_stdin_inherit_tty() {
+        // This is the half run on the terminal, which has to be in a process
+        // of its own to have a controlling terminal
+        if env::var("CWRAP_TTY_TEST").is_ok() {
+            let args =
+                Args::parse_from(["cwrap", "--stdin-inherit", "-t", "5", "--", "head", "-n1"]);
+            let opts = RunOptions::from_args(&args, Instant::now());
+            let run = CmdRun::run(&CmdState::new(&args.cmd, false), &opts);
+            assert_eq!(None, run.killed);
+            assert_eq!((0, "hello\n"), (run.exit_code, &run.stdout[..]));
+            return;
+        }
+
+        // Rerun just this test with the pty as its controlling terminal, like
+        // cwrap run from an interactive shell
+        let pty = Pty::open().unwrap();
+        let mut test = Command::new(env::current_exe().unwrap());
+        test.args(["--exact", "wlib::pty::tests::test_stdin_inherit_tty"])
+            .env("CWRAP_TTY_TEST", "1")
+            .stdin(pty.slave.try_clone().unwrap())
+            .stdout(pty.slave.try_clone().unwrap())
+            .stderr(pty.slave.try_clone().unwrap());
+        unsafe {
+            test.pre_exec(Pty::make_controlling);
+        }
+        let mut child = test.spawn().unwrap();
+        drop(test);
+        drop(pty.slave);
+
+        let mut master = pty.master;
+        master.write_all(b"hello\n").unwrap();
+        let reader = thread::spawn(move || {
+            let mut out = vec![];
+            // This ends with an error once the terminal is closed
+            master.read_to_end(&mut out).ok();
+            return String::from_utf8_lossy(&out).to_string();
+        });
+
+        let status = child.wait().unwrap();
+        let out = reader.join().unwrap();
+        assert!(status.success(), "{}", out);
+    }
+}
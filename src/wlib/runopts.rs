@@ -0,0 +1,163 @@
+use super::environ::EnvOptions;
+use super::limits::ResourceLimits;
+use super::priority::Priority;
+use crate::Args;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Where the command's stdin comes from
+#[derive(Clone, Debug, PartialEq)]
+pub enum StdinSource {
+    Null,
+    Inherit,
+    File(PathBuf),
+}
+
+/// The options that control how the command itself is run
+pub struct RunOptions {
+    pub timeout: usize,
+    /// The timeouts for the steps of a multi-step job, in order
+    pub step_timeouts: Vec<usize>,
+    /// When everything, including any retries or later steps, must be done
+    pub deadline: Option<Instant>,
+    pub idle_timeout: Option<Duration>,
+    pub limits: ResourceLimits,
+    pub priority: Priority,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub env: EnvOptions,
+    pub chdir: Option<PathBuf>,
+    pub umask: Option<u32>,
+    pub pty: bool,
+    pub strip_ansi: bool,
+    pub stdin: StdinSource,
+    /// Track descendants of the command that outlive it
+    pub subreaper: bool,
+    /// Kill, rather than wait for, descendants left behind by the command
+    pub kill_orphans: bool,
+    /// Descriptors, other than stdin, stdout and stderr, the command inherits
+    pub pass_fds: Vec<i32>,
+}
+
+impl RunOptions {
+    /// Extract the run options from the command-line args.  The '--deadline'
+    /// counts from `started`, when cwrap started.  A deadline too far off to
+    /// represent is no deadline at all.
+    pub fn from_args(args: &Args, started: Instant) -> Self {
+        let stdin = match &args.stdin_file {
+            Some(path) => StdinSource::File(path.clone()),
+            None if args.stdin_inherit => StdinSource::Inherit,
+            None => StdinSource::Null,
+        };
+
+        return Self {
+            timeout: args.timeout,
+            step_timeouts: args.step_timeout.clone(),
+            deadline: args.deadline.and_then(|d| started.checked_add(d)),
+            idle_timeout: args.idle_timeout,
+            limits: ResourceLimits {
+                mem: args.limit_mem,
+                cpu: args.limit_cpu,
+                nofile: args.limit_nofile,
+                fsize: args.limit_fsize,
+            },
+            priority: Priority {
+                nice: args.nice,
+                ionice: args.ionice,
+            },
+            user: args.user.clone(),
+            group: args.group.clone(),
+            env: EnvOptions {
+                clear: args.clear_env,
+                files: args.env_file.clone(),
+                vars: args.env.clone(),
+                path: args.path.clone(),
+                report: args.report_env,
+            },
+            chdir: args.chdir.clone(),
+            umask: args.umask,
+            pty: args.pty,
+            strip_ansi: args.strip_ansi,
+            stdin: stdin,
+            subreaper: args.subreaper,
+            kill_orphans: args.kill_orphans,
+            pass_fds: args.pass_fd.clone(),
+        };
+    }
+
+    /// The timeout for the given step of a multi-step job, or zero if it
+    /// has none of its own and is only limited by the job's timeout
+    pub fn step_timeout(&self, idx: usize) -> usize {
+        return *self.step_timeouts.get(idx).unwrap_or(&0);
+    }
+
+    /// When something started now has to be done by, given the '--timeout'
+    /// and the '--deadline'
+    pub fn limit(&self) -> Option<Instant> {
+        let timeout_at = match self.timeout {
+            0 => None,
+            t => Some(Instant::now() + Duration::from_secs(t as u64)),
+        };
+
+        return match (timeout_at, self.deadline) {
+            (Some(t), Some(d)) => Some(t.min(d)),
+            (t, d) => t.or(d),
+        };
+    }
+
+    /// The time left until the '--deadline', if there is one
+    pub fn time_left(&self) -> Option<Duration> {
+        return self
+            .deadline
+            .map(|d| d.saturating_duration_since(Instant::now()));
+    }
+
+    /// The run options for the given cwrap options, for a run starting now
+    #[cfg(test)]
+    pub fn for_test(args: &[&str]) -> Self {
+        use clap::Parser;
+
+        let mut argv = vec!["cwrap"];
+        argv.extend(args);
+        argv.push("true");
+        return Self::from_args(&Args::parse_from(argv), Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_time_left() {
+        let args = Args::parse_from(["cwrap", "true"]);
+        assert_eq!(
+            None,
+            RunOptions::from_args(&args, Instant::now()).time_left()
+        );
+
+        let args = Args::parse_from(["cwrap", "--deadline", "1m", "true"]);
+        let left = RunOptions::from_args(&args, Instant::now())
+            .time_left()
+            .unwrap();
+        assert!(left > Duration::from_secs(59) && left <= Duration::from_secs(60));
+
+        // The clock starts when cwrap does, not when the options are made
+        let started = Instant::now() - Duration::from_secs(45);
+        let left = RunOptions::from_args(&args, started).time_left().unwrap();
+        assert!(left <= Duration::from_secs(15));
+
+        let started = Instant::now() - Duration::from_secs(90);
+        assert_eq!(
+            Some(Duration::ZERO),
+            RunOptions::from_args(&args, started).time_left()
+        );
+
+        let args = Args::parse_from(["cwrap", "--deadline", "1e19", "true"]);
+        assert_eq!(
+            None,
+            RunOptions::from_args(&args, Instant::now()).time_left()
+        );
+    }
+}
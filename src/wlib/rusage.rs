@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+
+/// The resources consumed by a single run of the child, as reported by the
+/// kernel when the child is reaped.  The counters keep the kernel's type,
+/// which is only 32 bits on some targets.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResourceUsage {
+    pub user_time: f64,
+    pub sys_time: f64,
+    /// The peak resident set size.  The kernel carries this over from the
+    /// parent at the fork, so it's never less than cwrap's own RSS at that
+    /// point (around 10MB) even for a tiny command.
+    pub max_rss_kb: libc::c_long,
+    pub in_blocks: libc::c_long,
+    pub out_blocks: libc::c_long,
+    pub vol_ctx_switches: libc::c_long,
+    pub invol_ctx_switches: libc::c_long,
+}
+
+impl ResourceUsage {
+    pub fn from_rusage(usage: &libc::rusage) -> Self {
+        return Self {
+            user_time: Self::tv_to_secs(&usage.ru_utime),
+            sys_time: Self::tv_to_secs(&usage.ru_stime),
+            // On Linux, ru_maxrss is already in kilobytes
+            max_rss_kb: usage.ru_maxrss,
+            in_blocks: usage.ru_inblock,
+            out_blocks: usage.ru_oublock,
+            vol_ctx_switches: usage.ru_nvcsw,
+            invol_ctx_switches: usage.ru_nivcsw,
+        };
+    }
+
+    /// Combine the usage of another run with this one, as for the steps of a
+    /// job.  The max RSS is the largest of the two rather than the sum.
+    pub fn add(&mut self, other: &Self) {
+        self.user_time += other.user_time;
+        self.sys_time += other.sys_time;
+        self.max_rss_kb = self.max_rss_kb.max(other.max_rss_kb);
+        self.in_blocks += other.in_blocks;
+        self.out_blocks += other.out_blocks;
+        self.vol_ctx_switches += other.vol_ctx_switches;
+        self.invol_ctx_switches += other.invol_ctx_switches;
+    }
+
+    /// Add the resource usage lines to a report
+    pub fn add_report(&self, rep: &mut String) {
+        rep.push_str(&format!(
+            "CPU Time (seconds): {:.2} user, {:.2} system\n",
+            self.user_time, self.sys_time,
+        ));
+        rep.push_str(&format!("Max RSS: {} KB\n", self.max_rss_kb));
+        rep.push_str(&format!(
+            "Block I/O: {} in, {} out\n",
+            self.in_blocks, self.out_blocks,
+        ));
+        rep.push_str(&format!(
+            "Context Switches: {} voluntary, {} involuntary\n",
+            self.vol_ctx_switches, self.invol_ctx_switches,
+        ));
+    }
+
+    fn tv_to_secs(tv: &libc::timeval) -> f64 {
+        return tv.tv_sec as f64 + tv.tv_usec as f64 / 1_000_000.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn test_from_rusage() {
+        let mut usage: libc::rusage = unsafe { mem::zeroed() };
+        usage.ru_utime.tv_sec = 1;
+        usage.ru_utime.tv_usec = 500_000;
+        usage.ru_stime.tv_usec = 250_000;
+        usage.ru_maxrss = 2048;
+        usage.ru_inblock = 3;
+        usage.ru_oublock = 4;
+        usage.ru_nvcsw = 5;
+        usage.ru_nivcsw = 6;
+
+        let ru = ResourceUsage::from_rusage(&usage);
+        assert_eq!(1.5, ru.user_time);
+        assert_eq!(0.25, ru.sys_time);
+        assert_eq!(2048, ru.max_rss_kb);
+
+        let mut rep = String::new();
+        ru.add_report(&mut rep);
+        assert!(rep.contains("1.50 user, 0.25 system"));
+        assert!(rep.contains("Block I/O: 3 in, 4 out"));
+        assert!(rep.contains("5 voluntary, 6 involuntary"));
+    }
+
+    #[test]
+    fn test_add() {
+        let mut usage: libc::rusage = unsafe { mem::zeroed() };
+        usage.ru_utime.tv_sec = 1;
+        usage.ru_maxrss = 2048;
+        usage.ru_nvcsw = 5;
+        let mut total = ResourceUsage::from_rusage(&usage);
+
+        usage.ru_maxrss = 1024;
+        total.add(&ResourceUsage::from_rusage(&usage));
+        assert_eq!(2.0, total.user_time);
+        assert_eq!(2048, total.max_rss_kb);
+        assert_eq!(10, total.vol_ctx_switches);
+    }
+}
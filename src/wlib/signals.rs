@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+// This is the state shared between the signal handling thread and the
+// thread running the command, so that termination signals can be forwarded
+// to the child and the interrupted run recorded.
+
+/// What to pass to kill() to signal the running child, which is the negated
+/// process group if it has its own, or 0 if there isn't one
+static CHILD: AtomicI32 = AtomicI32::new(0);
+/// The termination signal we received, or 0 if we haven't received one
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+/// Set once we've started running the command, after which the main thread
+/// is responsible for shutting down cleanly
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Register the running child, as what to pass to kill() to signal it.  If a
+/// signal arrived while it was being started, it's forwarded immediately.
+pub fn set_child(target: i32) {
+    CHILD.store(target, Ordering::SeqCst);
+    if let Some(sig) = received() {
+        unsafe { libc::kill(target, sig) };
+    }
+}
+
+pub fn clear_child() {
+    CHILD.store(0, Ordering::SeqCst);
+}
+
+/// Record the signal and forward it to the child, returning whether there
+/// was a child to forward it to
+pub fn forward(sig: i32) -> bool {
+    RECEIVED.store(sig, Ordering::SeqCst);
+    let target = CHILD.load(Ordering::SeqCst);
+    if target != 0 {
+        unsafe { libc::kill(target, sig) };
+        return true;
+    }
+
+    return false;
+}
+
+/// Return the termination signal received, if any
+pub fn received() -> Option<i32> {
+    return match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    };
+}
+
+/// Mark that the command is about to be run.  From here on, a signal is
+/// handled by the main thread, which records the interrupted run.
+pub fn set_running() {
+    RUNNING.store(true, Ordering::SeqCst);
+}
+
+pub fn is_running() -> bool {
+    return RUNNING.load(Ordering::SeqCst);
+}
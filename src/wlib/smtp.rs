@@ -145,8 +145,9 @@ impl SMTPOptions {
     }
 }
 
-/// Convenience function for the sending of the email.
-pub fn send_email(body: &str, opts: &SMTPOptions) -> Result<()> {
+/// Convenience function for the sending of the email.  If a subject is
+/// passed, it's used instead of the one in the options.
+pub fn send_email(body: &str, subject: Option<&str>, opts: &SMTPOptions) -> Result<()> {
     if !opts.send_email {
         return Ok(());
     }
@@ -155,7 +156,7 @@ pub fn send_email(body: &str, opts: &SMTPOptions) -> Result<()> {
         .from(opts.email_from.as_str().parse()?)
         .reply_to(opts.email_from.as_str().parse()?)
         .to(opts.recipient[0].parse()?)
-        .subject(opts.subject.clone())
+        .subject(subject.unwrap_or(&opts.subject))
         .header(ContentType::TEXT_PLAIN);
 
     // Add the rest of the recipients
@@ -3,7 +3,7 @@ extern crate md5;
 use super::errors::lockfile;
 use super::helpers::sanitize_path;
 use std::convert::From;
-use std::fs::{remove_file, File, OpenOptions};
+use std::fs::{self, remove_file, File, OpenOptions};
 use std::io::{self, Read, Write};
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::PathBuf;
@@ -63,6 +63,17 @@ impl StateFile {
         return ret;
     }
 
+    /// Generate a name for the statefile of a multi-step job, which is:
+    ///     steps-<first command basename>.<md5 of the steps>
+    /// The steps are joined with a NUL, which can't be in any of them, so the
+    /// job can't share state with a shell string made by joining the steps.
+    pub fn gen_steps_name(steps: &Vec<String>) -> String {
+        let hash_str = format!("{:x}", md5::compute(steps.join("\0").as_bytes()));
+        let first = steps[0].split(" ").collect::<Vec<&str>>()[0];
+
+        return format!("steps-{}.{}", sanitize_path(first, '-'), hash_str);
+    }
+
     /// Set a specific lockfile for this run rather than use the auto-gen file
     pub fn overwrite_lockfile(&mut self, p: PathBuf) {
         self.lockfile = p;
@@ -127,8 +138,23 @@ impl StateFile {
         return Ok(());
     }
 
+    /// Check whether the lockfile was created by this process
+    pub fn owns_lock(&self) -> bool {
+        return match fs::read_to_string(&self.lockfile) {
+            Ok(pid) => pid.trim() == process::id().to_string(),
+            Err(_) => false,
+        };
+    }
+
+    /// Remove the lockfile, if it was created by this process.  A lockfile
+    /// belonging to another instance is left alone.
     pub fn unlock(&self) -> lockfile::Result<()> {
-        if self.lockfile.exists() {
+        if self.lockfile.exists() && !self.owns_lock() {
+            debug!(
+                "Not removing lockfile owned by another process: {}",
+                &self.lockfile.display()
+            );
+        } else if self.lockfile.exists() {
             debug!("Removing lockfile at: {}", &self.lockfile.display());
             if let Err(e) = remove_file(&self.lockfile) {
                 return Err(lockfile::LockError::new(format!(
@@ -161,4 +187,38 @@ mod tests {
         tmp.push(lockname);
         assert_eq!(s.lockfile, tmp);
     }
+
+    #[test]
+    fn test_gen_steps_name() {
+        let steps = vec!["make".to_string(), "make install".to_string()];
+        let name = StateFile::gen_steps_name(&steps);
+        assert!(name.starts_with("steps-make."));
+
+        // Neither the same string run with '-g' nor the steps split up
+        // differently share the state
+        assert_ne!(
+            StateFile::gen_name(&vec!["make make install".to_string()], true),
+            name
+        );
+        let split = vec!["make make".to_string(), "install".to_string()];
+        assert_ne!(StateFile::gen_steps_name(&split), name);
+    }
+
+    #[test]
+    fn test_unlock_owned() {
+        let mut sf = StateFile::from_strs("cwrap-test-unlock", "/tmp");
+        sf.overwrite_lockfile(PathBuf::from("/tmp/cwrap-test-unlock.lock"));
+
+        // A lock held by some other pid must not be removed
+        fs::write(&sf.lockfile, "1").unwrap();
+        assert!(!sf.owns_lock());
+        assert!(sf.unlock().is_ok());
+        assert!(sf.lockfile.exists());
+        remove_file(&sf.lockfile).unwrap();
+
+        assert!(sf.lock().is_ok());
+        assert!(sf.owns_lock());
+        assert!(sf.unlock().is_ok());
+        assert!(!sf.lockfile.exists());
+    }
 }
@@ -0,0 +1,170 @@
+use crate::sleep_ms;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// How long orphans get to exit after a SIGTERM before they are killed
+const TERM_GRACE: Duration = Duration::from_secs(5);
+
+/// A process left behind by the command
+#[derive(Clone, Debug, PartialEq)]
+pub struct Orphan {
+    pub pid: libc::pid_t,
+    pub cmdline: String,
+}
+
+impl Orphan {
+    pub fn desc(&self) -> String {
+        return format!("{}: {}", self.pid, self.cmdline);
+    }
+}
+
+/// Make this process a child subreaper, so that anything the command
+/// daemonizes is reparented to us rather than init
+pub fn enable() -> io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    return Ok(());
+}
+
+/// Pull the parent pid out of the contents of /proc/<pid>/stat.  The
+/// command name is in parens and can contain anything, including spaces and
+/// parens, so the fields are found after the last ')'.
+fn parse_ppid(stat: &str) -> Option<libc::pid_t> {
+    let idx = stat.rfind(')')?;
+    // The fields after the name are the state and then the ppid
+    return stat[idx + 1..].split_whitespace().nth(1)?.parse().ok();
+}
+
+fn cmdline(pid: libc::pid_t) -> String {
+    if let Ok(raw) = fs::read(format!("/proc/{}/cmdline", pid)) {
+        let args: Vec<String> = raw
+            .split(|b| *b == 0)
+            .filter(|a| !a.is_empty())
+            .map(|a| String::from_utf8_lossy(a).to_string())
+            .collect();
+        if !args.is_empty() {
+            return args.join(" ");
+        }
+    }
+
+    // Kernel threads and zombies have no command line
+    return match fs::read_to_string(format!("/proc/{}/comm", pid)) {
+        Ok(comm) => format!("[{}]", comm.trim()),
+        Err(_) => "[unknown]".to_string(),
+    };
+}
+
+/// Find all the live descendants of this process
+pub fn descendants() -> Vec<Orphan> {
+    let mut children: HashMap<libc::pid_t, Vec<libc::pid_t>> = HashMap::new();
+    let entries = match fs::read_dir("/proc") {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+
+    for entry in entries.flatten() {
+        let pid = match entry.file_name().to_string_lossy().parse::<libc::pid_t>() {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+
+        let stat = match fs::read_to_string(entry.path().join("stat")) {
+            Ok(s) => s,
+            // The process is already gone
+            Err(_) => continue,
+        };
+
+        // Zombies are reaped, not waited on
+        if stat
+            .rfind(')')
+            .map(|i| stat[i + 1..].trim_start().starts_with('Z'))
+            == Some(true)
+        {
+            continue;
+        }
+
+        if let Some(ppid) = parse_ppid(&stat) {
+            children.entry(ppid).or_default().push(pid);
+        }
+    }
+
+    let mut ret = vec![];
+    let mut todo = vec![unsafe { libc::getpid() }];
+    while let Some(parent) = todo.pop() {
+        if let Some(kids) = children.get(&parent) {
+            for kid in kids {
+                ret.push(Orphan {
+                    pid: *kid,
+                    cmdline: cmdline(*kid),
+                });
+                todo.push(*kid);
+            }
+        }
+    }
+    ret.sort_by_key(|o| o.pid);
+
+    return ret;
+}
+
+/// Reap any orphans that have exited, without blocking
+pub fn reap() {
+    let mut status: libc::c_int = 0;
+    while unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) } > 0 {}
+}
+
+/// Wait for all descendants to exit, returning false if they were still
+/// running when the deadline passed or `stop` returned true
+pub fn wait_all<F: Fn() -> bool>(deadline: Option<Instant>, stop: F) -> bool {
+    loop {
+        reap();
+        if descendants().is_empty() {
+            return true;
+        }
+
+        if deadline.map(|d| Instant::now() >= d) == Some(true) || stop() {
+            return false;
+        }
+
+        sleep_ms!(100);
+    }
+}
+
+/// Terminate all descendants, giving them a chance to exit cleanly before
+/// they are killed.  Neither wait goes past the `deadline`, if there is one.
+pub fn kill_all(deadline: Option<Instant>) {
+    for orphan in descendants() {
+        unsafe { libc::kill(orphan.pid, libc::SIGTERM) };
+    }
+
+    if wait_all(grace_end(deadline), || false) {
+        return;
+    }
+
+    for orphan in descendants() {
+        unsafe { libc::kill(orphan.pid, libc::SIGKILL) };
+    }
+    wait_all(grace_end(deadline), || false);
+}
+
+/// When the grace period starting now ends, cut short by the deadline
+fn grace_end(deadline: Option<Instant>) -> Option<Instant> {
+    let end = Instant::now() + TERM_GRACE;
+    return Some(deadline.map_or(end, |d| end.min(d)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ppid() {
+        assert_eq!(Some(1), parse_ppid("42 (bash) S 1 42 42 0 -1"));
+        assert_eq!(Some(7), parse_ppid("42 (a) b (c)) R 7 42 42 0 -1"));
+        assert_eq!(None, parse_ppid("42 bash S"));
+        assert_eq!(None, parse_ppid("42 (bash) S"));
+    }
+}